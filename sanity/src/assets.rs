@@ -0,0 +1,63 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{self, eyre};
+
+use crate::fs::PathExt;
+
+/// Name of the JSON file inside `dist` mapping original asset names to fingerprinted ones.
+pub const MANIFEST: &str = "asset-manifest.json";
+
+/// Whether a file is a stylesheet, script, image, or font that `asset()` can refer to.
+pub fn is_asset(path: &Path) -> bool {
+    matches!(
+        path.extension_str(),
+        Some(
+            "css"
+                | "js"
+                | "mjs"
+                | "png"
+                | "jpg"
+                | "jpeg"
+                | "gif"
+                | "webp"
+                | "avif"
+                | "svg"
+                | "ico"
+                | "woff"
+                | "woff2"
+                | "ttf"
+                | "otf"
+                | "eot"
+        )
+    )
+}
+
+/// Writes a copy of a file with its content hash in the name, e.g. `style.3f2a9c1d.css`.
+pub fn fingerprint(path: &Path) -> eyre::Result<PathBuf> {
+    let data = fs::read(path)?;
+    let hash = format!("{:016x}", fnv1a(&data));
+
+    let stem = path.file_stem().and_then(|x| x.to_str());
+    let stem = stem.ok_or_else(|| eyre!("Weird asset name: {:?}", path))?;
+    let name = match path.extension_str() {
+        Some(ext) => format!("{}.{}.{}", stem, &hash[..8], ext),
+        None => format!("{}.{}", stem, &hash[..8]),
+    };
+
+    let hashed = path.with_file_name(name);
+    // Same name, same contents.
+    if !hashed.exists() {
+        fs::write(&hashed, data)?;
+    }
+
+    Ok(hashed)
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -1,9 +1,9 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     fs,
     path::{Path, PathBuf},
-    sync::Mutex,
-    time::Instant,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
 };
 
 use color_eyre::eyre::{self, eyre};
@@ -12,19 +12,38 @@ use rayon::prelude::*;
 use tokio::sync::{RwLock, RwLockReadGuard};
 
 use crate::{
+    assets, config, data,
+    deps::{self, Graph, Mtimes, Rendered},
+    diagnostic::TemplateError,
+    frontmatter,
     fs::PathExt,
     jinja2::JinjaEnvironment,
-    lua::Shebang as LuaShebang,
-    minify,
+    lua::{self, Hook, Render, Shebang as LuaShebang},
+    manifest, markdown, minify,
     paths::{self, PathExt as _},
+    sitemap, xml,
 };
 
 static BUILD: RwLock<()> = RwLock::const_new(());
 
+/// Dependency graph of the last successful build, used to skip outputs that are up to date.
+static GRAPH: Mutex<Option<Graph>> = Mutex::new(None);
+
+/// Compiled templates, kept around so that rebuilds only recompile the ones that changed.
+static JINJA: Mutex<Option<Arc<JinjaEnvironment>>> = Mutex::new(None);
+
+/// Report of the last build as HTML, if it failed.
+static FAILURE: Mutex<Option<String>> = Mutex::new(None);
+
 pub async fn lock() -> RwLockReadGuard<'static, ()> {
     BUILD.read().await
 }
 
+/// Returns the report of the last build as HTML, if it failed.
+pub fn failure() -> Option<String> {
+    FAILURE.lock().unwrap().clone()
+}
+
 pub async fn run() -> eyre::Result<()> {
     let start = if crate::args().profile_build_times {
         Some(Instant::now())
@@ -40,10 +59,15 @@ pub async fn run() -> eyre::Result<()> {
     let result = match result {
         Err(err) => {
             error!("{}", err);
+            *FAILURE.lock().unwrap() = Some(match err.downcast_ref::<TemplateError>() {
+                Some(err) => err.to_html(),
+                None => format!("<pre>{}</pre>", xml::escape(&format!("{:#}", err))),
+            });
             Err(err)
         }
         Ok(()) => {
             info!("Site built!");
+            *FAILURE.lock().unwrap() = None;
             Ok(())
         }
     };
@@ -64,10 +88,24 @@ fn run_inner() -> eyre::Result<()> {
         ));
     }
 
-    let state = State::try_new()?;
+    // A failed build forgets the graph, so the next one starts from scratch.
+    let previous = GRAPH.lock().unwrap().take().unwrap_or_default();
+
+    let jinja = (JINJA.lock().unwrap())
+        .get_or_insert_with(|| Arc::new(JinjaEnvironment::new()))
+        .clone();
+    jinja.reset();
+
+    let state = State::try_new(previous, jinja)?;
+    state.register_templates(&paths::www()?)?;
+    state.jinja.prune();
+    state.load_data()?;
     state.walk(&paths::www()?)?;
-    state.finalize()?;
+    state.fingerprint()?;
+    let graph = state.finalize()?;
+    manifest::update(&graph.outputs, !crate::args().no_prune)?;
 
+    *GRAPH.lock().unwrap() = Some(graph);
     Ok(())
 }
 
@@ -96,20 +134,173 @@ fn nuke_inner() -> eyre::Result<()> {
     Ok(())
 }
 
+/// Reads an HTML or text file to be copied into `dist`, leaving its front matter behind.
+fn read_copy(path: &Path) -> eyre::Result<Vec<u8>> {
+    let data = fs::read(path)?;
+    let Ok(source) = std::str::from_utf8(&data) else {
+        return Ok(data);
+    };
+
+    let front = frontmatter::split(source)
+        .map_err(|err| eyre!("{}: bad front matter: {}", path.display_simple(), err))?;
+    match front.meta {
+        Some(_) => Ok(front.body.as_bytes().to_vec()),
+        None => Ok(data),
+    }
+}
+
+/// An HTML file waiting for the `on_output` hooks, which are only known once every script has run.
+struct Deferred {
+    source: PathBuf,
+    target: PathBuf,
+    /// `None` for copies which were up to date as of the walk.
+    contents: Option<Vec<u8>>,
+}
+
 struct State {
     lua: Mutex<LuaShebang>,
-    jinja: JinjaEnvironment,
+    jinja: Arc<JinjaEnvironment>,
+    /// Markdown pages waiting for their layouts to be registered.
+    pages: Mutex<Vec<Render>>,
+    deferred: Mutex<Vec<Deferred>>,
+    previous: Graph,
+    /// Modification times of the input files as of this build.
+    mtimes: Mtimes,
+    styles: Mutex<HashMap<PathBuf, HashSet<PathBuf>>>,
+    /// Every file written to (or left up to date in) `dist` by this build, along with the file it was produced from.
+    outputs: Mutex<HashMap<PathBuf, PathBuf>>,
+    assets: Mutex<HashMap<String, String>>,
+    data: Mutex<minijinja::Value>,
 }
 
 impl State {
-    fn try_new() -> eyre::Result<Self> {
+    fn try_new(previous: Graph, jinja: Arc<JinjaEnvironment>) -> eyre::Result<Self> {
         Ok(Self {
-            lua: Mutex::new(LuaShebang::try_new()?),
-            jinja: JinjaEnvironment::new(),
+            lua: Mutex::new(LuaShebang::try_new(jinja.clone())?),
+            jinja,
+            pages: Mutex::new(Vec::new()),
+            deferred: Mutex::new(Vec::new()),
+            previous,
+            mtimes: deps::snapshot(&paths::www()?)?,
+            styles: Mutex::new(HashMap::new()),
+            outputs: Mutex::new(HashMap::new()),
+            assets: Mutex::new(HashMap::new()),
+            data: Mutex::new(minijinja::Value::UNDEFINED),
         })
     }
 
+    /// Whether a rendered page goes into the sitemap, i.e. it's HTML and wasn't opted out with `sitemap: false`.
+    fn in_sitemap(&self, item: &Render) -> bool {
+        if item.target.extension_str() != Some("html") {
+            return false;
+        }
+
+        let opted_out = |x: &minijinja::Value| {
+            x.get_attr("sitemap")
+                .is_ok_and(|x| x == minijinja::Value::from(false))
+        };
+
+        let page = item.context.get_attr("page").unwrap_or_default();
+        let meta = self.jinja.meta(&item.template).unwrap_or_default();
+        !(opted_out(&item.context) || opted_out(&page) || opted_out(&meta))
+    }
+
+    /// Latest modification time of the files a page was rendered from.
+    fn lastmod(
+        &self,
+        item: &Render,
+        reads: &HashMap<PathBuf, HashSet<PathBuf>>,
+    ) -> Option<SystemTime> {
+        let reads = reads.get(&item.source).into_iter().flatten();
+        std::iter::once(&item.source)
+            .chain(reads)
+            .filter_map(|x| self.mtimes.get(x))
+            .max()
+            .copied()
+    }
+
+    /// Takes note of an output, refusing to produce the same file twice.
+    fn produced(&self, path: &Path, source: &Path) -> eyre::Result<()> {
+        let previous = match self.outputs.lock() {
+            Ok(mut outputs) => outputs.insert(path.to_path_buf(), source.to_path_buf()),
+            Err(_) => return Err(eyre!("damn it")),
+        };
+
+        match previous {
+            Some(other) if other == source => Err(eyre!(
+                "{} is produced more than once by {}",
+                path.display_simple(),
+                source.display_simple()
+            )),
+            Some(other) => Err(eyre!(
+                "{} is produced by both {} and {}",
+                path.display_simple(),
+                other.display_simple(),
+                source.display_simple()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether an input file changed since the previous build.
+    fn changed(&self, path: &Path) -> bool {
+        self.previous.mtimes.get(path) != self.mtimes.get(path)
+    }
+
+    /// Whether a render's output has to be regenerated.
+    fn outdated(&self, item: &Render, reads: &HashMap<PathBuf, HashSet<PathBuf>>) -> bool {
+        let rendered = self.previous.renders.get(&item.target);
+        if !item.target.exists() || rendered.is_none_or(|x| !x.matches(item)) {
+            return true;
+        }
+
+        let Some(mut deps) = self.jinja.dependencies(&item.template) else {
+            return true;
+        };
+        deps.insert(item.source.clone());
+        deps.extend(reads.get(&item.source).into_iter().flatten().cloned());
+
+        deps.iter().any(|x| self.changed(x))
+    }
+
+    /// Registers every template up front, so that Lua scripts can render any of them.
+    fn register_templates(&self, dir: &Path) -> eyre::Result<()> {
+        for child in fs::read_dir(dir)? {
+            let child = child?.path().canonicalize()?;
+
+            if child.is_dir() {
+                self.register_templates(&child)?;
+            } else if let Some("j2") = child.extension_str() {
+                self.jinja.register(&child)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads the data directory for templates and scripts to use.
+    fn load_data(&self) -> eyre::Result<()> {
+        let data = data::load_dir(&paths::data()?)?;
+        self.jinja.set_data(data.clone());
+
+        match self.lua.lock() {
+            Ok(lua) => lua.set_data(&data)?,
+            Err(_) => return Err(eyre!("damn it")),
+        };
+
+        match self.data.lock() {
+            Ok(mut x) => *x = data,
+            Err(_) => return Err(eyre!("damn it")),
+        };
+        Ok(())
+    }
+
     fn walk(&self, branch: &Path) -> eyre::Result<()> {
+        // Loaded up front instead.
+        if branch == paths::data()? {
+            return Ok(());
+        }
+
         let dest = paths::dist()?.join(branch.strip_prefix(paths::www()?)?);
 
         if branch.is_dir() {
@@ -134,32 +325,110 @@ impl State {
     fn process_file(&self, branch: &Path, mut dest: PathBuf) -> eyre::Result<()> {
         let ext = branch.extension_str();
         let underscored = branch.is_underscored();
+        if ext == Some("html") {
+            dest = paths::pretty(dest);
+        }
         let recent = branch.more_recent_than(&dest)?;
 
         match ext {
-            Some("j2") => {
-                self.jinja.register(branch)?;
-            }
+            Some("j2") => {} // registered before the walk
             Some("scss") if !underscored => {
-                let opts = grass::Options::default().load_path(paths::www()?);
-                let data = grass::from_path(branch, &opts)?;
                 dest.set_extension("css");
-                fs::write(dest, data)?;
+
+                let deps = match self.previous.styles.get(&dest) {
+                    Some(deps) if dest.exists() && !deps.iter().any(|x| self.changed(x)) => {
+                        deps.clone()
+                    }
+                    _ => {
+                        let style = if crate::args().prod() && crate::config().minify.css {
+                            grass::OutputStyle::Compressed
+                        } else {
+                            grass::OutputStyle::Expanded
+                        };
+                        let recorder = deps::Recorder::default();
+                        let opts = grass::Options::default()
+                            .load_path(paths::www()?)
+                            .style(style)
+                            .fs(&recorder);
+                        let data = grass::from_path(branch, &opts)?;
+                        fs::write(&dest, data)?;
+                        recorder.into_inner()
+                    }
+                };
+
+                self.produced(&dest, branch)?;
+                match self.styles.lock() {
+                    Ok(mut styles) => styles.insert(dest, deps),
+                    Err(_) => return Err(eyre!("damn it")),
+                };
             }
             Some("lua") if !underscored => match self.lua.lock() {
                 Ok(lua) => lua.process(branch)?,
                 Err(_) => return Err(eyre!("damn it")),
             },
-            Some("js") if !recent => {
-                let data = fs::read(&branch)?;
+            Some("md") if !underscored => {
+                let source = fs::read_to_string(branch)?;
+                let front = frontmatter::split(&source).map_err(|err| {
+                    eyre!("{}: bad front matter: {}", branch.display_simple(), err)
+                })?;
+                let doc = markdown::parse(front.body);
+
+                // Front matter takes precedence over the layout comment.
+                let layout = front
+                    .meta
+                    .as_ref()
+                    .and_then(|meta| meta.get_attr("layout").ok())
+                    .and_then(|layout| layout.as_str().map(str::to_string))
+                    .or(doc.layout.map(str::to_string));
+
+                // Without a layout there's no page to speak of, e.g. a README or a draft.
+                let Some(template) = layout else {
+                    fs::copy(branch, &dest)?;
+                    return self.produced(&dest, branch);
+                };
+
+                let content = markdown::render(doc.body);
+                let dest = paths::pretty(dest.with_extension("html"));
+                let mut context = context! {
+                    content => minijinja::Value::from_safe_string(content),
+                };
+                if let Some(meta) = front.meta {
+                    context = merge_maps([context, context! { page => meta }]);
+                }
+
+                match self.pages.lock() {
+                    Ok(mut pages) => pages.push(Render {
+                        template,
+                        target: dest,
+                        context,
+                        source: branch.to_path_buf(),
+                    }),
+                    Err(_) => return Err(eyre!("damn it")),
+                }
+            }
+            Some("js") if recent => {
+                self.produced(&dest, branch)?;
+            }
+            Some("js") => {
+                let data = fs::read(branch)?;
                 minify::write(&dest, minify::Type::Js, data)?;
+                self.produced(&dest, branch)?;
+            }
+            Some("html") => {
+                let data = if recent {
+                    None
+                } else {
+                    Some(read_copy(branch)?)
+                };
+                self.defer(branch, dest, data)?;
             }
-            Some("html") if !recent => {
-                let data = fs::read(&branch)?;
-                minify::write(&dest, minify::Type::Html, data)?;
+            Some("txt") if !underscored => {
+                fs::write(&dest, read_copy(branch)?)?;
+                self.produced(&dest, branch)?;
             }
             _ if !underscored => {
-                fs::copy(branch, dest)?;
+                fs::copy(branch, &dest)?;
+                self.produced(&dest, branch)?;
             }
             _ => {}
         }
@@ -167,9 +436,82 @@ impl State {
         Ok(())
     }
 
-    fn finalize(self) -> eyre::Result<()> {
-        let names: HashSet<_> = self.jinja.all();
+    fn defer(&self, source: &Path, target: PathBuf, contents: Option<Vec<u8>>) -> eyre::Result<()> {
+        match self.deferred.lock() {
+            Ok(mut deferred) => deferred.push(Deferred {
+                source: source.to_path_buf(),
+                target,
+                contents,
+            }),
+            Err(_) => return Err(eyre!("damn it")),
+        };
+        Ok(())
+    }
+
+    /// Writes an output, passing it through the matching `on_output` hooks before minifying it.
+    fn emit(
+        &self,
+        target: &Path,
+        data: Vec<u8>,
+        minify: Option<minify::Type>,
+        hooks: &[Hook],
+    ) -> eyre::Result<()> {
+        if let Some(parent) = target.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+
+        let data = lua::run_hooks(hooks, &target.dist_name()?, data)?;
+
+        match minify {
+            Some(kind) => minify::write(target, kind, data),
+            None => Ok(fs::write(target, data)?),
+        }
+    }
+
+    /// Collects the assets `asset()` can point at, making fingerprinted copies of them if enabled.
+    fn fingerprint(&self) -> eyre::Result<()> {
+        let enabled = crate::config().fingerprint;
+
+        let outputs: Vec<PathBuf> = match self.outputs.lock() {
+            Ok(outputs) => outputs
+                .keys()
+                .filter(|x| assets::is_asset(x))
+                .cloned()
+                .collect(),
+            Err(_) => return Err(eyre!("damn it")),
+        };
+
+        let assets = outputs
+            .par_iter()
+            .map(|path| {
+                let url = if enabled {
+                    let hashed = assets::fingerprint(path)?;
+                    self.produced(&hashed, path)?;
+                    hashed.dist_name()?
+                } else {
+                    path.dist_name()?
+                };
+                Ok((path.dist_name()?, url))
+            })
+            .collect::<eyre::Result<HashMap<_, _>>>()?;
+
+        if enabled {
+            let manifest = paths::dist()?.join(assets::MANIFEST);
+            let sorted: BTreeMap<_, _> = assets.iter().collect();
+            fs::write(&manifest, serde_json::to_string_pretty(&sorted)?)?;
+            self.produced(&manifest, &paths::root()?.join(config::FILENAME))?;
+        }
+
+        self.jinja.set_assets(assets.clone());
+        match self.assets.lock() {
+            Ok(mut x) => *x = assets,
+            Err(_) => return Err(eyre!("damn it")),
+        };
+
+        Ok(())
+    }
 
+    fn finalize(self) -> eyre::Result<Graph> {
         let lua = match self.lua.lock() {
             Ok(lua) => lua.state(),
             Err(_) => return Err(eyre!("damn it")),
@@ -177,23 +519,169 @@ impl State {
 
         let globals = minijinja::Value::from_serialize(&lua.global_context);
         let merge = |x: &minijinja::Value| merge_maps([globals.clone(), x.clone()]);
+        let assets = match self.assets.lock() {
+            Ok(mut assets) => std::mem::take(&mut *assets),
+            Err(_) => return Err(eyre!("damn it")),
+        };
+        let data = match self.data.lock() {
+            Ok(mut data) => std::mem::take(&mut *data),
+            Err(_) => return Err(eyre!("damn it")),
+        };
+
+        // Hooks, filters and functions can't be compared between builds, so every output is redone once the scripts registering them change.
+        let extensions: HashSet<_> = (lua.hooks.iter().map(|x| x.source.clone()))
+            .chain(lua.extensions.iter().cloned())
+            .collect();
+        let extensions_changed = extensions != self.previous.extensions
+            || extensions.iter().any(|script| {
+                let reads = lua.reads.get(script).into_iter().flatten();
+                std::iter::once(script)
+                    .chain(reads)
+                    .any(|x| self.changed(x))
+            })
+            || self
+                .previous
+                .extension_reads
+                .iter()
+                .any(|x| self.changed(x));
+
+        let everything = extensions_changed
+            || globals != self.previous.globals
+            || data != self.previous.data
+            || assets != self.previous.assets;
+
+        let renders = Mutex::new(HashMap::new());
+        let render = |item: &Render| -> eyre::Result<()> {
+            self.produced(&item.target, &item.source)?;
+
+            if everything || self.outdated(item, &lua.reads) {
+                let ctx = merge(&item.context);
+                let data = (self.jinja.render_string(
+                    &item.template,
+                    &ctx,
+                    Some(&item.target),
+                    &item.source,
+                ))
+                .map_err(|err| match err.downcast::<TemplateError>() {
+                    Ok(err) => err.rendering(&item.target, &item.source).into(),
+                    Err(err) => err,
+                })?;
+                let minify = match item.target.extension_str() {
+                    Some("html") => Some(minify::Type::Html),
+                    _ => None,
+                };
+                self.emit(&item.target, data.into_bytes(), minify, &lua.hooks)?;
+            }
 
-        names.par_iter().try_for_each(|name| {
+            let rendered = Rendered::from(item);
+            renders
+                .lock()
+                .unwrap()
+                .insert(item.target.clone(), rendered);
+            Ok(())
+        };
+
+        let mut statics = Vec::new();
+        for name in self.jinja.all() {
             let target = paths::dist()?.join(&name);
 
             if !target.is_underscored() {
-                let ctx = merge(&context! {});
-                self.jinja.render(&name, &target, &ctx)?;
+                statics.push(Render {
+                    source: paths::www()?.join(format!("{}.j2", name)),
+                    template: name,
+                    target: paths::pretty(target),
+                    context: context! {},
+                });
             }
+        }
 
-            eyre::Result::<()>::Ok(())
+        statics.par_iter().try_for_each(render)?;
+        lua.render_queue.par_iter().try_for_each(render)?;
+
+        let pages = match self.pages.lock() {
+            Ok(pages) => pages,
+            Err(_) => return Err(eyre!("damn it")),
+        };
+        pages.par_iter().try_for_each(render)?;
+
+        let deferred = match self.deferred.lock() {
+            Ok(mut deferred) => std::mem::take(&mut *deferred),
+            Err(_) => return Err(eyre!("damn it")),
+        };
+        deferred.par_iter().try_for_each(|item| {
+            self.produced(&item.target, &item.source)?;
+
+            let data = match &item.contents {
+                Some(data) => data.clone(),
+                None if !extensions_changed => return Ok(()),
+                None => read_copy(&item.source)?,
+            };
+            self.emit(&item.target, data, Some(minify::Type::Html), &lua.hooks)
         })?;
 
-        lua.render_queue.par_iter().try_for_each(|item| {
-            let ctx = merge(&item.context);
-            self.jinja.render(&item.template, &item.target, &ctx)
+        lua.outputs.par_iter().try_for_each(|output| {
+            self.produced(&output.target, &output.source)?;
+
+            let minify = match output.target.extension_str() {
+                Some("html") => Some(minify::Type::Html),
+                Some("js") => Some(minify::Type::Js),
+                _ => None,
+            };
+            self.emit(&output.target, output.contents.clone(), minify, &lua.hooks)
+                .map_err(|err| {
+                    eyre!(
+                        "{}: failed to write {}: {}",
+                        output.source.display_simple(),
+                        output.target.display_simple(),
+                        err
+                    )
+                })
         })?;
 
-        Ok(())
+        // They ran against the blank state `state()` left behind, so that's where their reads ended up.
+        // Outputs that weren't redone this time still depend on what was read for them before.
+        let mut extension_reads: HashSet<_> = match self.lua.lock() {
+            Ok(lua) => lua.state().reads.into_values().flatten().collect(),
+            Err(_) => return Err(eyre!("damn it")),
+        };
+        if !extensions_changed {
+            extension_reads.extend(self.previous.extension_reads.iter().cloned());
+        }
+
+        if crate::config().sitemap.enabled {
+            let entries = statics
+                .iter()
+                .chain(&lua.render_queue)
+                .chain(pages.iter())
+                .filter(|item| self.in_sitemap(item))
+                .map(|item| sitemap::Entry {
+                    target: item.target.clone(),
+                    lastmod: self.lastmod(item, &lua.reads),
+                })
+                .collect();
+
+            match self.outputs.lock() {
+                Ok(mut outputs) => {
+                    let written = sitemap::write(entries, &outputs)?;
+                    let source = paths::root()?.join(config::FILENAME);
+                    outputs.extend(written.into_iter().map(|x| (x, source.clone())));
+                }
+                Err(_) => return Err(eyre!("damn it")),
+            }
+        }
+
+        drop(pages);
+
+        Ok(Graph {
+            mtimes: self.mtimes,
+            styles: self.styles.into_inner().unwrap(),
+            renders: renders.into_inner().unwrap(),
+            globals,
+            data,
+            extensions,
+            extension_reads,
+            outputs: self.outputs.into_inner().unwrap().into_keys().collect(),
+            assets,
+        })
     }
 }
@@ -0,0 +1,246 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use color_eyre::eyre::{self, eyre};
+use minijinja::Value as JValue;
+use serde::Deserialize;
+
+use crate::{Args, paths};
+
+pub const FILENAME: &str = "sanity.toml";
+
+/// Project configuration read from `sanity.toml` at the project root.
+///
+/// Every field is optional; a missing file is the same as an empty one.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Directory containing the site sources, relative to the project root.
+    pub input: PathBuf,
+    /// Directory the built site is written to, relative to the project root.
+    pub output: PathBuf,
+    /// Public URL of the deployed site, e.g. `https://example.org`.
+    pub base_url: Option<String>,
+    /// Write content-hashed copies of assets for `asset()` to point at.
+    pub fingerprint: bool,
+    /// Directory of JSON, TOML and YAML files exposed as `data`, relative to `input`.
+    pub data: PathBuf,
+    /// Write HTML pages as `about/index.html` instead of `about.html`, so they are served at `/about/`.
+    pub pretty_urls: bool,
+    pub server: Server,
+    pub minify: Minify,
+    pub sitemap: Sitemap,
+    pub lua: Lua,
+    /// Variables available inside all templates.
+    pub globals: HashMap<String, JValue>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub port: u16,
+}
+
+/// Per-filetype minification switches. Minification only ever happens in production builds.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Minify {
+    pub html: bool,
+    pub js: bool,
+    /// Off by default, which keeps the expanded output of the SCSS compiler.
+    pub css: bool,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sitemap {
+    /// Generate a `sitemap.xml` listing every HTML page. Requires `base_url`.
+    pub enabled: bool,
+    /// Also generate a `robots.txt` pointing at the sitemap.
+    pub robots: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Lua {
+    /// Take `io`, `os.execute` and the like away from scripts, and keep builtins from touching files outside `www` and `dist`.
+    pub sandbox: bool,
+    /// Capabilities to give back to scripts. `ffi` and `debug` have to be listed even outside the sandbox.
+    pub allow: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            input: PathBuf::from("www"),
+            output: PathBuf::from("dist"),
+            base_url: None,
+            fingerprint: false,
+            data: PathBuf::from("_data"),
+            pretty_urls: false,
+            server: Server::default(),
+            minify: Minify::default(),
+            sitemap: Sitemap::default(),
+            lua: Lua::default(),
+            globals: HashMap::new(),
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self { port: 8000 }
+    }
+}
+
+impl Default for Minify {
+    fn default() -> Self {
+        Self {
+            html: true,
+            js: true,
+            css: false,
+        }
+    }
+}
+
+impl Default for Sitemap {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            robots: true,
+        }
+    }
+}
+
+impl Config {
+    /// Reads `sanity.toml` (if present) and applies command-line overrides on top of it.
+    pub fn load(args: &Args) -> eyre::Result<Self> {
+        let path = paths::root()?.join(FILENAME);
+
+        let mut config: Self = if path.exists() {
+            let source = fs::read_to_string(&path)?;
+            toml::from_str(&source).map_err(|err| eyre!("{}: {}", FILENAME, err))?
+        } else {
+            Self::default()
+        };
+
+        if let Some(input) = &args.www {
+            config.input = input.clone();
+        }
+        if let Some(output) = &args.dist {
+            config.output = output.clone();
+        }
+        if let Some(base_url) = &args.base_url {
+            config.base_url = Some(base_url.clone());
+        }
+        if args.no_minify {
+            config.minify = Minify {
+                html: false,
+                js: false,
+                css: false,
+            };
+        }
+
+        // Flags can set these too, so the error doesn't blame the file.
+        check_dirs(&paths::root()?, &config.input, &config.output)?;
+
+        crate::lua::sandbox::validate(&config.lua.allow)
+            .map_err(|err| eyre!("{}: {}", FILENAME, err))?;
+
+        if let Some(base_url) = &mut config.base_url {
+            while base_url.ends_with('/') {
+                base_url.pop();
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Makes sure building or cleaning `output` can't touch the sources or the rest of the project.
+fn check_dirs(root: &Path, input: &Path, output: &Path) -> eyre::Result<()> {
+    let input_dir = resolve(root, input);
+    let output_dir = resolve(root, output);
+
+    if root.starts_with(&output_dir) {
+        return Err(eyre!(
+            "`output` ({}) can't be the project root or contain it",
+            output.display()
+        ));
+    }
+    if input_dir.starts_with(&output_dir) {
+        return Err(eyre!(
+            "`input` ({}) can't be inside `output` ({})",
+            input.display(),
+            output.display()
+        ));
+    }
+    if output_dir.starts_with(&input_dir) {
+        return Err(eyre!(
+            "`output` ({}) can't be inside `input` ({})",
+            output.display(),
+            input.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Resolves a directory relative to the project root, following symlinks if it exists.
+fn resolve(root: &Path, dir: &Path) -> PathBuf {
+    let mut path = PathBuf::new();
+    for comp in root.join(dir).components() {
+        match comp {
+            Component::ParentDir => {
+                path.pop();
+            }
+            Component::CurDir => {}
+            comp => path.push(comp),
+        }
+    }
+
+    path.canonicalize().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(input: &str, output: &str) -> eyre::Result<()> {
+        check_dirs(Path::new("/project"), Path::new(input), Path::new(output))
+    }
+
+    #[test]
+    fn accepts_separate_dirs() {
+        assert!(check("www", "dist").is_ok());
+        assert!(check("src/www", "build/dist").is_ok());
+        assert!(check("www", "../elsewhere/dist").is_ok());
+        assert!(check("www", "/tmp/site").is_ok());
+        assert!(check("www", "www-dist").is_ok());
+    }
+
+    #[test]
+    fn rejects_project_root() {
+        for output in [".", "", "./", "www/..", "/project", "..", "/"] {
+            let err = check("www", output).unwrap_err().to_string();
+            assert!(err.starts_with("`output`"), "{}: {}", output, err);
+        }
+    }
+
+    #[test]
+    fn rejects_overlapping_dirs() {
+        assert!(check("www", "www").is_err());
+        assert!(check("www", "./www/").is_err());
+        assert!(check("www", "www/dist").is_err());
+        assert!(check("dist/www", "dist").is_err());
+        assert!(check("www", "dist/../www/out").is_err());
+
+        let err = check("site/www", "site").unwrap_err().to_string();
+        assert!(err.starts_with("`input`"), "{}", err);
+        let err = check("www", "www/dist").unwrap_err().to_string();
+        assert!(err.starts_with("`output`"), "{}", err);
+    }
+}
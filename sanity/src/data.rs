@@ -0,0 +1,205 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use color_eyre::eyre::{self, eyre};
+use minijinja::Value as JValue;
+use serde::Deserialize;
+
+use crate::{fs::PathExt as _, paths::PathExt as _};
+
+/// Loads every JSON, TOML and YAML file inside a directory into a map keyed by file stem, with subdirectories becoming nested maps.
+///
+/// A missing directory is the same as an empty one.
+pub fn load_dir(dir: &Path) -> eyre::Result<JValue> {
+    let mut map = BTreeMap::new();
+    if !dir.is_dir() {
+        return Ok(JValue::from(map));
+    }
+
+    for child in fs::read_dir(dir)? {
+        let child = child?.path();
+        let Some(stem) = child.file_stem().and_then(|x| x.to_str()) else {
+            continue;
+        };
+
+        let value = if child.is_dir() {
+            load_dir(&child)?
+        } else {
+            let parse = match child.extension_str() {
+                Some("json") => json,
+                Some("toml") => toml,
+                Some("yaml" | "yml") => yaml,
+                _ => continue,
+            };
+
+            let source = fs::read_to_string(&child)?;
+            parse(&source).map_err(|err| eyre!("{}: {}", child.display_simple(), err))?
+        };
+
+        if map.insert(stem.to_string(), value).is_some() {
+            return Err(eyre!(
+                "{}: more than one data file is named `{}`",
+                dir.display_simple(),
+                stem
+            ));
+        }
+    }
+
+    Ok(JValue::from(map))
+}
+
+/// Parses a JSON document into a template value.
+pub fn json(source: &str) -> eyre::Result<JValue> {
+    let value: serde_json::Value = serde_json::from_str(source)?;
+    Ok(JValue::from_serialize(value))
+}
+
+/// Parses a YAML document into a template value.
+pub fn yaml(source: &str) -> eyre::Result<JValue> {
+    Ok(serde_yaml_ng::from_str(source)?)
+}
+
+/// Parses a TOML document into a template value. Datetimes are turned into strings.
+pub fn toml(source: &str) -> eyre::Result<JValue> {
+    let table: toml::Table = toml::from_str(source).map_err(|err| match err.span() {
+        Some(span) => {
+            let (line, column) = position(source, span.start);
+            eyre!("{} at line {} column {}", err.message(), line, column)
+        }
+        None => eyre!("{}", err.message()),
+    })?;
+
+    Ok(from_toml(toml::Value::Table(table)))
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvOptions {
+    /// Treat the first row as column names and turn every other row into a map.
+    pub header: bool,
+    /// Turn cells that look like numbers or booleans into those.
+    pub coerce: bool,
+    pub delimiter: String,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            header: true,
+            coerce: false,
+            delimiter: ",".to_string(),
+        }
+    }
+}
+
+/// Parses a CSV document into a list of rows: maps keyed by the header row, or plain lists without one.
+pub fn csv(source: &str, options: &CsvOptions) -> eyre::Result<JValue> {
+    let delimiter = match options.delimiter.as_bytes() {
+        &[x] => x,
+        _ => return Err(eyre!("CSV delimiter must be a single ASCII character")),
+    };
+
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(options.header)
+        .delimiter(delimiter)
+        .from_reader(source.as_bytes());
+
+    let headers = match options.header {
+        true => Some(reader.headers().map_err(csv_error)?.clone()),
+        false => None,
+    };
+
+    let cell = |x: &str| match options.coerce {
+        true => coerce(x),
+        false => JValue::from(x),
+    };
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(csv_error)?;
+
+        let row = match &headers {
+            Some(headers) => JValue::from_iter(
+                headers
+                    .iter()
+                    .zip(record.iter())
+                    .map(|(k, v)| (k.to_string(), cell(v))),
+            ),
+            None => JValue::from(record.iter().map(cell).collect::<Vec<_>>()),
+        };
+        rows.push(row);
+    }
+
+    Ok(JValue::from(rows))
+}
+
+fn coerce(cell: &str) -> JValue {
+    let looks_numeric = !cell.is_empty()
+        && cell
+            .chars()
+            .all(|x| x.is_ascii_digit() || matches!(x, '-' | '+' | '.' | 'e' | 'E'));
+
+    if looks_numeric {
+        if let Ok(x) = cell.parse::<i64>() {
+            return JValue::from(x);
+        }
+        if let Ok(x) = cell.parse::<f64>() {
+            return JValue::from(x);
+        }
+    }
+
+    match cell {
+        "true" => JValue::from(true),
+        "false" => JValue::from(false),
+        _ => JValue::from(cell),
+    }
+}
+
+fn csv_error(err: csv::Error) -> eyre::Report {
+    match err.kind() {
+        csv::ErrorKind::UnequalLengths {
+            pos: Some(pos),
+            expected_len,
+            len,
+        } => eyre!(
+            "expected {} fields, found {} at line {}",
+            expected_len,
+            len,
+            pos.line()
+        ),
+        csv::ErrorKind::Utf8 {
+            pos: Some(pos),
+            err,
+        } => eyre!(
+            "invalid UTF-8 at line {} column {}",
+            pos.line(),
+            err.field() + 1
+        ),
+        _ => eyre!("{}", err),
+    }
+}
+
+/// 1-based line and column of a byte offset.
+fn position(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rsplit('\n')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .count()
+        + 1;
+    (line, column)
+}
+
+fn from_toml(value: toml::Value) -> JValue {
+    match value {
+        toml::Value::String(x) => JValue::from(x),
+        toml::Value::Integer(x) => JValue::from(x),
+        toml::Value::Float(x) => JValue::from(x),
+        toml::Value::Boolean(x) => JValue::from(x),
+        toml::Value::Datetime(x) => JValue::from(x.to_string()),
+        toml::Value::Array(x) => JValue::from(x.into_iter().map(from_toml).collect::<Vec<_>>()),
+        toml::Value::Table(x) => JValue::from_iter(x.into_iter().map(|(k, v)| (k, from_toml(v)))),
+    }
+}
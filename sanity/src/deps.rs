@@ -0,0 +1,110 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use color_eyre::eyre;
+use minijinja::Value as JValue;
+
+use crate::{fs::PathExt, lua::Render};
+
+pub type Mtimes = HashMap<PathBuf, SystemTime>;
+
+/// What a successful build produced, and out of what.
+#[derive(Default)]
+pub struct Graph {
+    /// Modification times of every input file as of the build.
+    pub mtimes: Mtimes,
+    /// Compiled stylesheets and the SCSS files they were compiled from.
+    pub styles: HashMap<PathBuf, HashSet<PathBuf>>,
+    /// Rendered templates by their output path.
+    pub renders: HashMap<PathBuf, Rendered>,
+    /// Global template variables injected from Lua.
+    pub globals: JValue,
+    /// Contents of the data directory.
+    pub data: JValue,
+    /// Every file the build produced inside `dist`.
+    pub outputs: HashSet<PathBuf>,
+    /// Asset URLs handed to `asset()`.
+    pub assets: HashMap<String, String>,
+    /// Scripts which registered `on_output` hooks or template filters and functions.
+    pub extensions: HashSet<PathBuf>,
+    /// Files read by those hooks, filters and functions, which is only known once they have run.
+    pub extension_reads: HashSet<PathBuf>,
+}
+
+pub struct Rendered {
+    pub template: String,
+    pub context: JValue,
+}
+
+impl Rendered {
+    pub fn matches(&self, item: &Render) -> bool {
+        self.template == item.template && self.context == item.context
+    }
+}
+
+impl From<&Render> for Rendered {
+    fn from(item: &Render) -> Self {
+        Self {
+            template: item.template.clone(),
+            context: item.context.clone(),
+        }
+    }
+}
+
+/// Takes note of every file's modification time inside a directory, recursively.
+pub fn snapshot(dir: &Path) -> eyre::Result<Mtimes> {
+    let mut mtimes = HashMap::new();
+    snapshot_inner(dir, &mut mtimes)?;
+    Ok(mtimes)
+}
+
+fn snapshot_inner(dir: &Path, mtimes: &mut Mtimes) -> eyre::Result<()> {
+    for child in fs::read_dir(dir)? {
+        let child = child?.path().canonicalize()?;
+
+        if child.is_dir() {
+            snapshot_inner(&child, mtimes)?;
+        } else {
+            let modified = child.last_modified()?;
+            mtimes.insert(child, modified);
+        }
+    }
+
+    Ok(())
+}
+
+/// A `grass` filesystem which remembers every file the compiler read.
+#[derive(Debug, Default)]
+pub struct Recorder(Mutex<HashSet<PathBuf>>);
+
+impl Recorder {
+    pub fn into_inner(self) -> HashSet<PathBuf> {
+        self.0.into_inner().unwrap()
+    }
+}
+
+impl grass::Fs for Recorder {
+    fn is_dir(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let path = path.canonicalize()?;
+        let data = fs::read(&path)?;
+        self.0.lock().unwrap().insert(path);
+        Ok(data)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+}
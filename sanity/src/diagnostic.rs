@@ -0,0 +1,195 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+};
+
+use crate::{fs::PathExt as _, paths::PathExt as _, xml};
+
+/// Lines of source shown above the failing one.
+const CONTEXT_LINES: usize = 2;
+
+/// A failed template render, with everything needed to track the problem down.
+pub struct TemplateError {
+    message: String,
+    /// Where it went wrong, if minijinja knows.
+    location: Option<Location>,
+    /// The `include` tags leading to the failing template, innermost first.
+    included_from: Vec<Location>,
+    /// The rendered template and the ones it extends, in that order.
+    ancestry: Vec<String>,
+    /// Output path and source of the render that failed, if it was headed for `dist`.
+    rendering: Option<(PathBuf, PathBuf)>,
+}
+
+struct Location {
+    name: String,
+    line: usize,
+    /// 1-based column and width of the offending span, if known.
+    span: Option<(usize, usize)>,
+    /// Numbered source lines ending with the failing one.
+    excerpt: Vec<(usize, String)>,
+}
+
+impl TemplateError {
+    pub fn new(err: &minijinja::Error, ancestry: Vec<String>) -> Self {
+        // Errors from included templates are wrapped by the including one, outermost first.
+        let mut chain = Vec::new();
+        let mut next: Option<&(dyn Error + 'static)> = Some(err);
+        while let Some(current) = next {
+            if let Some(x) = current.downcast_ref::<minijinja::Error>() {
+                chain.push(x);
+            }
+            next = current.source();
+        }
+
+        let innermost = chain.pop().unwrap_or(err);
+        let message = match innermost.detail() {
+            Some(detail) => format!("{}: {}", innermost.kind(), detail),
+            None => innermost.kind().to_string(),
+        };
+
+        Self {
+            message,
+            location: Location::of(innermost),
+            included_from: chain.iter().rev().filter_map(|x| Location::of(x)).collect(),
+            ancestry,
+            rendering: None,
+        }
+    }
+
+    /// Records which render failed.
+    pub fn rendering(mut self, target: &Path, source: &Path) -> Self {
+        self.rendering = Some((target.to_path_buf(), source.to_path_buf()));
+        self
+    }
+
+    /// The report as lines of text, with the failing source line flagged.
+    fn lines(&self) -> Vec<(String, bool)> {
+        let mut lines = vec![(format!("template error: {}", self.message), false)];
+
+        if let Some(location) = &self.location {
+            lines.push((format!("  --> {}", location), false));
+
+            let width = location.line.to_string().len();
+            let gutter = " ".repeat(width);
+
+            if !location.excerpt.is_empty() {
+                lines.push((format!(" {} |", gutter), false));
+            }
+            for (number, text) in &location.excerpt {
+                let failing = *number == location.line;
+                lines.push((format!(" {:>width$} | {}", number, text), failing));
+            }
+            if let Some((column, len)) = location.span.filter(|_| !location.excerpt.is_empty()) {
+                let marker = format!("{}{}", " ".repeat(column - 1), "^".repeat(len));
+                lines.push((format!(" {} | {}", gutter, marker), false));
+            }
+        }
+
+        for location in &self.included_from {
+            lines.push((format!("  = included from {}", location), false));
+        }
+        for pair in self.ancestry.windows(2) {
+            lines.push((format!("  = {} extends {}", pair[0], pair[1]), false));
+        }
+
+        if let Some((target, source)) = &self.rendering {
+            let target = target.display_simple();
+            let line = match source.extension_str() {
+                Some("lua") => format!(
+                    "while rendering {}, queued by {}",
+                    target,
+                    source.display_simple()
+                ),
+                _ => format!(
+                    "while rendering {} from {}",
+                    target,
+                    source.display_simple()
+                ),
+            };
+            lines.push((format!("  = {}", line), false));
+        }
+
+        lines
+    }
+
+    /// The report as HTML for the dev-server's error page, with the failing source line highlighted.
+    pub fn to_html(&self) -> String {
+        let lines: Vec<_> = (self.lines().into_iter())
+            .map(|(text, failing)| match failing {
+                true => format!("<mark>{}</mark>", xml::escape(&text)),
+                false => xml::escape(&text),
+            })
+            .collect();
+        format!("<pre>{}</pre>", lines.join("\n"))
+    }
+}
+
+impl Location {
+    fn of(err: &minijinja::Error) -> Option<Self> {
+        let name = err.name()?.to_string();
+        let line = err.line()?;
+
+        let Some(source) = err.template_source() else {
+            return Some(Self {
+                name,
+                line,
+                span: None,
+                excerpt: Vec::new(),
+            });
+        };
+
+        let excerpt = (source.lines().enumerate())
+            .map(|(i, text)| (i + 1, text.to_string()))
+            .skip(line.saturating_sub(CONTEXT_LINES + 1))
+            .take_while(|(number, _)| *number <= line)
+            .collect();
+
+        let span = err
+            .range()
+            .filter(|x| x.start <= source.len())
+            .map(|range| {
+                let start = source[..range.start].rfind('\n').map_or(0, |x| x + 1);
+                let end = source[range.start..]
+                    .find('\n')
+                    .map_or(source.len(), |x| range.start + x)
+                    .min(range.end.max(range.start));
+
+                let column = source[start..range.start].chars().count() + 1;
+                let len = source[range.start..end].chars().count().max(1);
+                (column, len)
+            });
+
+        Some(Self {
+            name,
+            line,
+            span,
+            excerpt,
+        })
+    }
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span {
+            Some((column, _)) => write!(f, "{}:{}:{}", self.name, self.line, column),
+            None => write!(f, "{}:{}", self.name, self.line),
+        }
+    }
+}
+
+impl Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines: Vec<_> = self.lines().into_iter().map(|(x, _)| x).collect();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl fmt::Debug for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for TemplateError {}
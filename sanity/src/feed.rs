@@ -0,0 +1,321 @@
+use std::fmt::Write as _;
+
+use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, SecondsFormat, Utc};
+use color_eyre::eyre::{self, eyre};
+use serde::Deserialize;
+
+use crate::xml::escape;
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Feed {
+    pub title: String,
+    /// Output path relative to `dist`.
+    pub path: String,
+    #[serde(default)]
+    pub format: Format,
+    pub description: Option<String>,
+    /// Link to the site the feed belongs to. Defaults to `base_url`.
+    pub link: Option<String>,
+    pub author: Option<String>,
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Atom,
+    Rss,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub title: String,
+    pub link: String,
+    /// Publication date.
+    pub date: Option<Date>,
+    /// Last update date. Defaults to the publication date.
+    pub updated: Option<Date>,
+    /// Unique ID of the entry. Defaults to its link.
+    pub id: Option<String>,
+    pub summary: Option<String>,
+    /// Full contents of the entry as HTML.
+    pub content: Option<String>,
+    pub author: Option<String>,
+}
+
+/// Either a Unix timestamp or a date string in any common format.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum Date {
+    Timestamp(f64),
+    Text(String),
+}
+
+impl Feed {
+    pub fn to_xml(&self) -> eyre::Result<String> {
+        match self.format {
+            Format::Atom => self.atom(),
+            Format::Rss => self.rss(),
+        }
+    }
+
+    fn atom(&self) -> eyre::Result<String> {
+        let link = absolute(self.link.as_deref().unwrap_or("/"))?;
+        let own = absolute(&self.path)?;
+
+        let mut entries = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            // RFC 4287 wants an author for every entry, which the feed's own author covers.
+            if self.author.is_none() && entry.author.is_none() {
+                return Err(eyre!(
+                    "Atom feeds need an `author`, either for the whole feed or for every entry: {}",
+                    entry.link
+                ));
+            }
+
+            let published = entry.date.as_ref().map(parse_date).transpose()?;
+            let updated = entry.updated.as_ref().map(parse_date).transpose()?;
+            entries.push((entry, published, updated.or(published)));
+        }
+
+        let updated = entries
+            .iter()
+            .filter_map(|(_, _, updated)| *updated)
+            .max()
+            .unwrap_or_else(|| Utc::now().fixed_offset());
+
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(xml, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#)?;
+        writeln!(xml, "  <title>{}</title>", escape(&self.title))?;
+        if let Some(description) = &self.description {
+            writeln!(xml, "  <subtitle>{}</subtitle>", escape(description))?;
+        }
+        writeln!(xml, "  <id>{}</id>", escape(&own))?;
+        writeln!(xml, r#"  <link href="{}"/>"#, escape(&link))?;
+        writeln!(xml, r#"  <link rel="self" href="{}"/>"#, escape(&own))?;
+        writeln!(xml, "  <updated>{}</updated>", rfc3339(updated))?;
+        if let Some(author) = &self.author {
+            writeln!(xml, "  <author><name>{}</name></author>", escape(author))?;
+        }
+
+        for (entry, published, updated) in entries {
+            let link = absolute(&entry.link)?;
+            let id = entry.id.clone().unwrap_or_else(|| link.clone());
+            let updated = updated.ok_or_else(|| eyre!("Atom entries need a date: {}", link))?;
+
+            writeln!(xml, "  <entry>")?;
+            writeln!(xml, "    <title>{}</title>", escape(&entry.title))?;
+            writeln!(xml, "    <id>{}</id>", escape(&id))?;
+            writeln!(xml, r#"    <link href="{}"/>"#, escape(&link))?;
+            if let Some(published) = published {
+                writeln!(xml, "    <published>{}</published>", rfc3339(published))?;
+            }
+            writeln!(xml, "    <updated>{}</updated>", rfc3339(updated))?;
+            if let Some(author) = &entry.author {
+                writeln!(xml, "    <author><name>{}</name></author>", escape(author))?;
+            }
+            if let Some(summary) = &entry.summary {
+                writeln!(xml, "    <summary>{}</summary>", escape(summary))?;
+            }
+            if let Some(content) = &entry.content {
+                writeln!(
+                    xml,
+                    r#"    <content type="html">{}</content>"#,
+                    escape(content)
+                )?;
+            }
+            writeln!(xml, "  </entry>")?;
+        }
+
+        writeln!(xml, "</feed>")?;
+        Ok(xml)
+    }
+
+    fn rss(&self) -> eyre::Result<String> {
+        let link = absolute(self.link.as_deref().unwrap_or("/"))?;
+        let own = absolute(&self.path)?;
+        let description = self.description.as_deref().unwrap_or(&self.title);
+
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            xml,
+            r#"<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/elements/1.1/">"#
+        )?;
+        writeln!(xml, "  <channel>")?;
+        writeln!(xml, "    <title>{}</title>", escape(&self.title))?;
+        writeln!(xml, "    <link>{}</link>", escape(&link))?;
+        writeln!(
+            xml,
+            "    <description>{}</description>",
+            escape(description)
+        )?;
+        writeln!(
+            xml,
+            r#"    <atom:link href="{}" rel="self" type="application/rss+xml"/>"#,
+            escape(&own)
+        )?;
+
+        for entry in &self.entries {
+            let link = absolute(&entry.link)?;
+
+            writeln!(xml, "    <item>")?;
+            writeln!(xml, "      <title>{}</title>", escape(&entry.title))?;
+            writeln!(xml, "      <link>{}</link>", escape(&link))?;
+            match &entry.id {
+                Some(id) => writeln!(
+                    xml,
+                    r#"      <guid isPermaLink="false">{}</guid>"#,
+                    escape(id)
+                )?,
+                None => writeln!(xml, "      <guid>{}</guid>", escape(&link))?,
+            }
+            if let Some(date) = &entry.date {
+                let date = parse_date(date)?.to_rfc2822();
+                writeln!(xml, "      <pubDate>{}</pubDate>", date)?;
+            }
+            // RSS wants an email address here, so plain names go into `dc:creator` instead.
+            match &entry.author {
+                Some(author) if is_email(author) => {
+                    writeln!(xml, "      <author>{}</author>", escape(author))?
+                }
+                Some(author) => writeln!(xml, "      <dc:creator>{}</dc:creator>", escape(author))?,
+                None => {}
+            }
+            if let Some(description) = entry.content.as_ref().or(entry.summary.as_ref()) {
+                writeln!(
+                    xml,
+                    "      <description>{}</description>",
+                    escape(description)
+                )?;
+            }
+            writeln!(xml, "    </item>")?;
+        }
+
+        writeln!(xml, "  </channel>")?;
+        writeln!(xml, "</rss>")?;
+        Ok(xml)
+    }
+}
+
+/// Whether an RSS author starts with an email address, as in `jane@example.org (Jane Doe)`.
+fn is_email(author: &str) -> bool {
+    let address = author.split_whitespace().next().unwrap_or_default();
+    address.split_once('@').is_some_and(|(user, host)| {
+        !user.is_empty() && host.contains('.') && !host.starts_with('.')
+    })
+}
+
+/// Prefixes a site-relative link with `base_url`. Absolute links are left as they are.
+fn absolute(link: &str) -> eyre::Result<String> {
+    if link.contains("://") {
+        return Ok(link.to_string());
+    }
+
+    let base = crate::config()
+        .base_url
+        .as_ref()
+        .ok_or_else(|| eyre!("Feeds with relative links require `base_url` to be set"))?;
+
+    Ok(format!("{}/{}", base, link.trim_start_matches('/')))
+}
+
+pub fn parse_date(date: &Date) -> eyre::Result<DateTime<FixedOffset>> {
+    let text = match date {
+        Date::Timestamp(x) => {
+            let date = DateTime::from_timestamp(*x as i64, 0);
+            let date = date.ok_or_else(|| eyre!("Timestamp out of range: {}", x))?;
+            return Ok(date.fixed_offset());
+        }
+        Date::Text(x) => x.trim(),
+    };
+
+    if let Ok(date) = DateTime::parse_from_rfc3339(text) {
+        return Ok(date);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(text) {
+        return Ok(date);
+    }
+    for format in ["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"] {
+        if let Ok(date) = NaiveDateTime::parse_from_str(text, format) {
+            return Ok(date.and_utc().fixed_offset());
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        return Ok(date.and_time(Default::default()).and_utc().fixed_offset());
+    }
+
+    Err(eyre!("Unrecognized date: {:?}", text))
+}
+
+fn rfc3339(date: DateTime<FixedOffset>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(format: Format, author: Option<&str>, entry_author: Option<&str>) -> Feed {
+        Feed {
+            title: "Blog".to_string(),
+            path: "feed.xml".to_string(),
+            format,
+            description: None,
+            link: None,
+            author: author.map(str::to_string),
+            entries: vec![Entry {
+                title: "Post".to_string(),
+                link: "/post.html".to_string(),
+                date: Some(Date::Text("2024-01-02".to_string())),
+                updated: None,
+                id: None,
+                summary: None,
+                content: None,
+                author: entry_author.map(str::to_string),
+            }],
+        }
+    }
+
+    #[test]
+    fn atom_requires_author() {
+        crate::test_config();
+        assert!(feed(Format::Atom, None, None).to_xml().is_err());
+
+        let xml = feed(Format::Atom, Some("Jane"), None).to_xml().unwrap();
+        assert!(xml.contains("  <author><name>Jane</name></author>"));
+
+        let xml = feed(Format::Atom, None, Some("Joe")).to_xml().unwrap();
+        assert!(xml.contains("    <author><name>Joe</name></author>"));
+        assert!(xml.contains("<link href=\"https://example.org/post.html\"/>"));
+    }
+
+    #[test]
+    fn rss_authors() {
+        crate::test_config();
+        assert!(feed(Format::Rss, None, None).to_xml().is_ok());
+
+        let xml = feed(Format::Rss, None, Some("Jane Doe")).to_xml().unwrap();
+        assert!(xml.contains("<dc:creator>Jane Doe</dc:creator>"));
+        assert!(!xml.contains("<author>"));
+
+        let email = "jane@example.org (Jane Doe)";
+        let xml = feed(Format::Rss, None, Some(email)).to_xml().unwrap();
+        assert!(xml.contains("<author>jane@example.org (Jane Doe)</author>"));
+    }
+
+    #[test]
+    fn email_detection() {
+        assert!(is_email("jane@example.org"));
+        assert!(is_email("jane@example.org (Jane Doe)"));
+        assert!(!is_email("Jane Doe"));
+        assert!(!is_email("@jane"));
+        assert!(!is_email("Jane @ Home"));
+        assert!(!is_email(""));
+    }
+}
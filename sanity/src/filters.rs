@@ -0,0 +1,471 @@
+use std::{fmt::Write as _, path::PathBuf};
+
+use chrono::{FixedOffset, Local};
+use chrono_tz::Tz;
+use minijinja::{Environment, Error, ErrorKind, Value};
+
+use crate::{
+    feed::{self, Date},
+    paths,
+};
+
+/// Words per minute assumed by `reading_time`.
+const READING_SPEED: usize = 200;
+
+/// Elements whose contents `smartypants` leaves alone.
+const VERBATIM: &[&str] = &["pre", "code", "kbd", "script", "style"];
+
+/// Registers the filters and functions every template gets.
+pub fn register(env: &mut Environment) {
+    env.add_filter("date", date);
+    env.add_filter("slugify", slugify);
+    env.add_filter("truncate_words", truncate_words);
+    env.add_filter("striptags", striptags);
+    env.add_filter("reading_time", reading_time);
+    env.add_filter("absolute_url", absolute_url);
+    env.add_filter("markdown", markdown);
+    env.add_filter("smartypants", smartypants);
+    env.add_filter("json_pretty", json_pretty);
+
+    env.add_function("url", url);
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidOperation, message.into())
+}
+
+/// Formats a Unix timestamp or a date string with strftime-style specifiers, optionally converting it to `UTC`, `local` time, a fixed offset such as `+02:00`, or a time zone such as `Europe/Berlin`.
+fn date(value: Value, format: Option<String>, tz: Option<String>) -> Result<String, Error> {
+    let input = match value.as_str() {
+        Some(text) => Date::Text(text.to_string()),
+        None => match f64::try_from(value.clone()) {
+            Ok(x) => Date::Timestamp(x),
+            Err(_) => return Err(invalid(format!("can't format {} as a date", value.kind()))),
+        },
+    };
+    let date = feed::parse_date(&input).map_err(|err| invalid(err.to_string()))?;
+
+    let date = match tz.as_deref() {
+        None => date,
+        Some("UTC" | "utc") => date.to_utc().fixed_offset(),
+        Some("local") => date.with_timezone(&Local).fixed_offset(),
+        Some(zone) => match (zone.parse::<FixedOffset>(), zone.parse::<Tz>()) {
+            (Ok(offset), _) => date.with_timezone(&offset),
+            (_, Ok(tz)) => date.with_timezone(&tz).fixed_offset(),
+            _ => return Err(invalid(format!("unknown timezone: {}", zone))),
+        },
+    };
+
+    let format = format.as_deref().unwrap_or("%Y-%m-%d");
+    let mut out = String::new();
+    match write!(out, "{}", date.format(format)) {
+        Ok(()) => Ok(out),
+        Err(_) => Err(invalid(format!("bad date format: {}", format))),
+    }
+}
+
+/// Lowercases a string and joins its words with dashes, for use in URLs and anchors.
+fn slugify(value: &str) -> String {
+    let mut slug = String::with_capacity(value.len());
+
+    for c in value.chars().filter(|x| !matches!(x, '\'' | '’')) {
+        if c.is_alphanumeric() {
+            slug.extend(c.to_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    slug.trim_end_matches('-').to_string()
+}
+
+/// Keeps the first `count` words of a string, appending `end` (an ellipsis by default) if anything was cut.
+fn truncate_words(value: &str, count: usize, end: Option<String>) -> String {
+    let words: Vec<_> = value.split_whitespace().collect();
+    if words.len() <= count {
+        return value.to_string();
+    }
+
+    let end = end.as_deref().unwrap_or("…");
+    format!("{}{}", words[..count].join(" "), end)
+}
+
+/// Removes HTML tags and comments, decodes common entities, and collapses whitespace.
+fn striptags(value: &str) -> String {
+    let mut text = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        text.push(' ');
+
+        let closing = match rest[start..].starts_with("<!--") {
+            true => "-->",
+            false => ">",
+        };
+        rest = match rest[start..].find(closing) {
+            Some(end) => &rest[start + end + closing.len()..],
+            None => "",
+        };
+    }
+    text.push_str(rest);
+
+    let text = decode_entities(&text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let decoded = entity.and_then(|x| match x {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => match x.strip_prefix("#x").or_else(|| x.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => x.strip_prefix('#')?.parse().ok().and_then(char::from_u32),
+            },
+        });
+
+        match (entity, decoded) {
+            (Some(entity), Some(c)) => {
+                out.push(c);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Estimates the minutes it takes to read a text or HTML fragment, rounding up.
+fn reading_time(value: &str, speed: Option<usize>) -> Result<usize, Error> {
+    let speed = speed.unwrap_or(READING_SPEED);
+    if speed == 0 {
+        return Err(invalid("reading speed must be positive"));
+    }
+
+    let words = striptags(value).split_whitespace().count();
+    Ok(words.div_ceil(speed).max(1))
+}
+
+/// Prefixes a path with `base_url`. Links that are absolute already are left alone.
+fn absolute_url(value: &str) -> Result<String, Error> {
+    if value.contains("://") || value.starts_with("//") {
+        return Ok(value.to_string());
+    }
+
+    let Some(base) = &crate::config().base_url else {
+        return Err(invalid("absolute_url requires `base_url` to be set"));
+    };
+
+    Ok(format!("{}/{}", base, value.trim_start_matches('/')))
+}
+
+/// Links to a page by its path relative to `dist`, e.g. `/about/` for `about.html` with `pretty_urls` on and `/about.html` without.
+/// A `#fragment` or `?query` is kept, and external links are left alone.
+fn url(path: &str) -> String {
+    if path.contains("://") || path.starts_with("//") {
+        return path.to_string();
+    }
+
+    let (path, suffix) = path.split_at(path.find(['#', '?']).unwrap_or(path.len()));
+    if path.is_empty() {
+        return suffix.to_string();
+    }
+
+    let target = paths::pretty(PathBuf::from(path.trim_start_matches('/')));
+    let name = target.to_string_lossy().replace('\\', "/");
+    format!("{}{}", paths::link(&name), suffix)
+}
+
+/// Renders Markdown to HTML, safe to output as it is.
+fn markdown(value: &str) -> Value {
+    Value::from_safe_string(crate::markdown::render(value))
+}
+
+/// Turns straight quotes into curly ones, `--` and `---` into en and em dashes, and `...` into an ellipsis. Tags and code are left alone.
+fn smartypants(value: Value) -> Result<Value, Error> {
+    let Some(text) = value.as_str() else {
+        return Err(invalid(format!(
+            "can't apply smartypants to {}",
+            value.kind()
+        )));
+    };
+
+    let mut out = String::with_capacity(text.len());
+    let mut previous = None;
+    let mut verbatim: Option<&str> = None;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        if c == '<' {
+            let end = rest.find('>').map_or(rest.len(), |x| x + 1);
+            let tag = &rest[..end];
+
+            let name: String = (tag[1..].chars())
+                .take_while(|x| x.is_ascii_alphanumeric() || *x == '/')
+                .collect::<String>()
+                .to_ascii_lowercase();
+            verbatim = match (verbatim, name.strip_prefix('/')) {
+                (Some(open), Some(closed)) if open == closed => None,
+                (None, None) => VERBATIM.iter().copied().find(|x| *x == name),
+                (verbatim, _) => verbatim,
+            };
+
+            out.push_str(tag);
+            rest = &rest[end..];
+            continue;
+        }
+
+        let (replacement, len) = match c {
+            _ if verbatim.is_some() => (c, c.len_utf8()),
+            '-' if rest.starts_with("---") => ('—', 3),
+            '-' if rest.starts_with("--") => ('–', 2),
+            '.' if rest.starts_with("...") => ('…', 3),
+            '"' | '\'' => {
+                let opening = match previous {
+                    None => true,
+                    Some(x) => char::is_whitespace(x) || "([{-—–“‘".contains(x),
+                };
+                let quote = match (c, opening) {
+                    ('"', true) => '“',
+                    ('"', false) => '”',
+                    (_, true) => '‘',
+                    (_, false) => '’',
+                };
+                (quote, 1)
+            }
+            _ => (c, c.len_utf8()),
+        };
+
+        out.push(replacement);
+        previous = Some(replacement);
+        rest = &rest[len..];
+    }
+
+    Ok(match value.is_safe() {
+        true => Value::from_safe_string(out),
+        false => Value::from(out),
+    })
+}
+
+/// Serializes a value as indented JSON.
+fn json_pretty(value: Value) -> Result<String, Error> {
+    serde_json::to_string_pretty(&value).map_err(|err| invalid(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(date: &str, format: &str, tz: Option<&str>) -> Result<String, Error> {
+        super::date(
+            Value::from(date),
+            Some(format.to_string()),
+            tz.map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn date_defaults() {
+        assert_eq!(
+            date(Value::from("2024-01-02"), None, None).unwrap(),
+            "2024-01-02"
+        );
+        assert_eq!(date(Value::from(86400), None, None).unwrap(), "1970-01-02");
+        assert_eq!(
+            format("2024-01-02 10:30", "%B %-d, %Y %H:%M", None).unwrap(),
+            "January 2, 2024 10:30"
+        );
+    }
+
+    #[test]
+    fn date_keeps_offset_without_tz() {
+        let date = "2024-01-02T10:00:00+02:00";
+        assert_eq!(format(date, "%H:%M %:z", None).unwrap(), "10:00 +02:00");
+    }
+
+    #[test]
+    fn date_converts_timezones() {
+        let date = "2024-01-02T10:00:00+02:00";
+        assert_eq!(
+            format(date, "%H:%M %:z", Some("UTC")).unwrap(),
+            "08:00 +00:00"
+        );
+        assert_eq!(
+            format(date, "%H:%M %:z", Some("+05:30")).unwrap(),
+            "13:30 +05:30"
+        );
+        assert_eq!(
+            format(date, "%H:%M %:z", Some("-03:00")).unwrap(),
+            "05:00 -03:00"
+        );
+        assert_eq!(
+            format("2023-12-31T23:30:00Z", "%Y-%m-%d", Some("+01:00")).unwrap(),
+            "2024-01-01"
+        );
+    }
+
+    #[test]
+    fn date_converts_named_timezones() {
+        let winter = "2024-01-02T12:00:00Z";
+        let summer = "2024-07-02T12:00:00Z";
+        let zone = Some("Europe/Berlin");
+        assert_eq!(format(winter, "%H:%M %:z", zone).unwrap(), "13:00 +01:00");
+        assert_eq!(format(summer, "%H:%M %:z", zone).unwrap(), "14:00 +02:00");
+    }
+
+    #[test]
+    fn date_errors() {
+        assert!(format("2024-01-02", "%F", Some("Mars/Olympus")).is_err());
+        assert!(format("yesterday", "%F", None).is_err());
+        assert!(format("2024-01-02", "%Q", None).is_err());
+        assert!(date(Value::from(vec![1, 2]), None, None).is_err());
+    }
+
+    #[test]
+    fn slugify_words() {
+        assert_eq!(slugify("Don't Panic!"), "dont-panic");
+        assert_eq!(slugify("  Hello,   World -- again  "), "hello-world-again");
+        assert_eq!(slugify("It’s Straße 2"), "its-straße-2");
+        assert_eq!(slugify("?!"), "");
+    }
+
+    #[test]
+    fn truncate_words_cuts() {
+        assert_eq!(truncate_words("one two three", 2, None), "one two…");
+        assert_eq!(
+            truncate_words("one two three", 1, Some(" [more]".to_string())),
+            "one [more]"
+        );
+        assert_eq!(truncate_words("one  two", 2, None), "one  two");
+        assert_eq!(truncate_words("", 0, None), "");
+    }
+
+    #[test]
+    fn striptags_removes_markup() {
+        assert_eq!(
+            striptags("<p>Fish &amp; <b>chips</b></p>\n<!-- a > b -->\n<p>done</p>"),
+            "Fish & chips done"
+        );
+        assert_eq!(striptags("a<br>b"), "a b");
+        assert_eq!(striptags("unclosed <span"), "unclosed");
+    }
+
+    #[test]
+    fn striptags_decodes_entities() {
+        assert_eq!(
+            striptags("&lt;tag&gt; &quot;q&quot; &apos;a&#39; &#x41;&#X42;&nbsp;x"),
+            "<tag> \"q\" 'a' AB x"
+        );
+    }
+
+    #[test]
+    fn decode_entities_edge_cases() {
+        assert_eq!(decode_entities("&amp;amp;"), "&amp;");
+        assert_eq!(decode_entities("&#128512;"), "😀");
+        assert_eq!(
+            decode_entities("R&D; &bogus; & &#xZZ; &"),
+            "R&D; &bogus; & &#xZZ; &"
+        );
+        assert_eq!(decode_entities("&#1114112;"), "&#1114112;");
+    }
+
+    #[test]
+    fn reading_time_rounds_up() {
+        let words = |n: usize| vec!["word"; n].join(" ");
+        assert_eq!(reading_time(&words(400), None).unwrap(), 2);
+        assert_eq!(reading_time(&words(401), None).unwrap(), 3);
+        assert_eq!(reading_time(&words(50), Some(25)).unwrap(), 2);
+        assert_eq!(reading_time("", None).unwrap(), 1);
+        assert_eq!(reading_time("<p>one <em>two</em></p>", Some(2)).unwrap(), 1);
+        assert!(reading_time("text", Some(0)).is_err());
+    }
+
+    #[test]
+    fn absolute_url_prefixes_base() {
+        crate::test_config();
+        assert_eq!(absolute_url("/blog/").unwrap(), "https://example.org/blog/");
+        assert_eq!(
+            absolute_url("feed.xml").unwrap(),
+            "https://example.org/feed.xml"
+        );
+        assert_eq!(absolute_url("https://x.org/a").unwrap(), "https://x.org/a");
+        assert_eq!(
+            absolute_url("//cdn.x.org/a.js").unwrap(),
+            "//cdn.x.org/a.js"
+        );
+    }
+
+    #[test]
+    fn markdown_is_safe() {
+        let html = markdown("*hi* <b>there</b>");
+        assert!(html.is_safe());
+        assert_eq!(
+            html.as_str().unwrap().trim(),
+            "<p><em>hi</em> <b>there</b></p>"
+        );
+    }
+
+    #[test]
+    fn smartypants_punctuation() {
+        let out = smartypants(Value::from(
+            r#""Hi," she said -- it's 'fine'... --- or not"#,
+        ));
+        assert_eq!(
+            out.unwrap().as_str().unwrap(),
+            "“Hi,” she said – it’s ‘fine’… — or not"
+        );
+    }
+
+    #[test]
+    fn smartypants_skips_tags_and_code() {
+        let input = r#"<a href="x" title='y'>"link"</a> <pre class="z">"raw" -- <code>'x'</code> ...</pre> "after" <code>--</code>"#;
+        assert_eq!(
+            smartypants(Value::from(input)).unwrap().as_str().unwrap(),
+            r#"<a href="x" title='y'>“link”</a> <pre class="z">"raw" -- <code>'x'</code> ...</pre> “after” <code>--</code>"#
+        );
+        assert_eq!(
+            smartypants(Value::from("<PRE>'a'</PRE> 'b'</em>'s"))
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "<PRE>'a'</PRE> ‘b’</em>’s"
+        );
+    }
+
+    #[test]
+    fn smartypants_keeps_safeness() {
+        assert!(
+            smartypants(Value::from_safe_string("'a'".into()))
+                .unwrap()
+                .is_safe()
+        );
+        assert!(!smartypants(Value::from("'a'")).unwrap().is_safe());
+        assert!(smartypants(Value::from(1)).is_err());
+    }
+
+    #[test]
+    fn json_pretty_indents() {
+        let value = Value::from_serialize(serde_json::json!({ "a": [1, "b"] }));
+        assert_eq!(
+            json_pretty(value).unwrap(),
+            "{\n  \"a\": [\n    1,\n    \"b\"\n  ]\n}"
+        );
+    }
+}
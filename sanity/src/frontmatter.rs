@@ -0,0 +1,58 @@
+use color_eyre::eyre;
+use minijinja::Value as JValue;
+
+use crate::data;
+
+/// A text file split into its front matter and the rest of its contents.
+pub struct Document<'a> {
+    pub meta: Option<JValue>,
+    pub body: &'a str,
+    /// Number of lines taken up by the front matter, fences included.
+    pub lines: usize,
+}
+
+/// Splits off an optional front-matter block: YAML fenced with `---` or TOML fenced with `+++`.
+///
+/// A block without a closing fence isn't considered front matter.
+pub fn split(source: &str) -> eyre::Result<Document<'_>> {
+    let none = Document {
+        meta: None,
+        body: source,
+        lines: 0,
+    };
+
+    let Some((fence, rest)) = ["---", "+++"]
+        .into_iter()
+        .find_map(|fence| open(source, fence).map(|rest| (fence, rest)))
+    else {
+        return Ok(none);
+    };
+
+    let mut offset = 0;
+
+    for (lines, line) in (2..).zip(rest.split_inclusive('\n')) {
+        if line.trim_end() == fence {
+            let raw = &rest[..offset];
+            let meta = match fence {
+                "---" => data::yaml(raw)?,
+                _ => data::toml(raw)?,
+            };
+
+            return Ok(Document {
+                meta: Some(meta),
+                body: &rest[offset + line.len()..],
+                lines,
+            });
+        }
+
+        offset += line.len();
+    }
+
+    Ok(none)
+}
+
+fn open<'a>(source: &'a str, fence: &str) -> Option<&'a str> {
+    let rest = source.strip_prefix(fence)?;
+    rest.strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))
+}
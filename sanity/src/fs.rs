@@ -14,6 +14,8 @@ pub trait PathExt {
     fn last_modified(&self) -> eyre::Result<SystemTime>;
     fn more_recent_than(&self, other: &Path) -> eyre::Result<bool>;
     fn template_name(&self) -> eyre::Result<String>;
+    fn www_name(&self) -> eyre::Result<String>;
+    fn dist_name(&self) -> eyre::Result<String>;
 }
 
 impl<T: AsRef<Path>> PathExt for T {
@@ -38,22 +40,34 @@ impl<T: AsRef<Path>> PathExt for T {
     }
 
     fn template_name(&self) -> eyre::Result<String> {
-        let mut name = String::new();
-
-        let base = self.as_ref().to_path_buf().with_extension("");
-        let components = base.strip_prefix(paths::www()?)?.components();
-
-        for comp in components {
-            let Component::Normal(x) = comp else {
-                continue;
-            };
-            if !name.is_empty() {
-                name += "/";
-            }
-            let x = x.as_encoded_bytes().iter().cloned().collect();
-            name += &String::from_utf8(x)?;
-        }
+        self.as_ref().with_extension("").www_name()
+    }
+
+    fn www_name(&self) -> eyre::Result<String> {
+        relative_name(self.as_ref(), &paths::www()?)
+    }
 
-        Ok(name)
+    fn dist_name(&self) -> eyre::Result<String> {
+        relative_name(self.as_ref(), &paths::dist()?)
     }
 }
+
+/// Path relative to `base` with forward slashes.
+fn relative_name(path: &Path, base: &Path) -> eyre::Result<String> {
+    let mut name = String::new();
+
+    let components = path.strip_prefix(base)?.components();
+
+    for comp in components {
+        let Component::Normal(x) = comp else {
+            continue;
+        };
+        if !name.is_empty() {
+            name += "/";
+        }
+        let x = x.as_encoded_bytes().to_vec();
+        name += &String::from_utf8(x)?;
+    }
+
+    Ok(name)
+}
@@ -1,40 +1,276 @@
 use std::{
     collections::{HashMap, HashSet},
     fs,
-    path::Path,
-    sync::RwLock,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
 };
 
+use chrono::{DateTime, SecondsFormat, Utc};
 use color_eyre::eyre::{self, eyre};
-use minijinja::{Environment, Error, ErrorKind, context, value::merge_maps};
+use minijinja::{
+    Environment, Error, ErrorKind, context,
+    value::{Rest, merge_maps},
+};
+
+use crate::{
+    diagnostic::TemplateError,
+    filters, frontmatter,
+    fs::PathExt,
+    paths::{self, PathExt as _},
+};
 
-use crate::{fs::PathExt, minify};
+/// A filter or function defined outside of Rust, called with every argument it was given.
+pub type Callable =
+    Arc<dyn Fn(&[minijinja::Value]) -> Result<minijinja::Value, Error> + Send + Sync>;
 
+type Callables = Arc<RwLock<HashMap<String, Callable>>>;
+
+/// Asset URLs by their path relative to `dist`.
+type Assets = Arc<HashMap<String, String>>;
+
+/// Templates along with a compiled environment for them, which is kept across builds and only recompiles what changed.
 pub struct JinjaEnvironment {
-    templates: RwLock<HashMap<String, String>>,
+    /// Template sources by name, shared with the environment's loader.
+    templates: Arc<RwLock<HashMap<String, String>>>,
+    /// Templates registered during the current build. The rest are forgotten by `prune`.
+    registered: RwLock<HashSet<String>>,
+    /// Front matter of each template that has it, exposed as `page` when rendering that template.
+    meta: RwLock<HashMap<String, minijinja::Value>>,
+    deps: RwLock<HashMap<String, Deps>>,
+    /// URLs of assets relative to `dist`, for the `asset()` function. Unknown until every file has been processed.
+    assets: Arc<RwLock<Option<Assets>>>,
+    /// When the current build started, exposed as `page.built`.
+    built: RwLock<DateTime<Utc>>,
+    /// Contents of the data directory, exposed as `data`.
+    data: RwLock<minijinja::Value>,
+    /// Filters and functions registered from Lua during the current build.
+    filters: Callables,
+    functions: Callables,
+    /// Names with a stand-in in the environment calling whatever Lua registered under them, so that nothing ever has to be removed from it.
+    stand_ins: RwLock<HashSet<String>>,
+    /// Renders clone this and let go of the lock, so that registering things from inside a render can't deadlock.
+    env: RwLock<Arc<Environment<'static>>>,
+}
+
+struct Deps {
+    /// Source file the template was read from.
+    path: PathBuf,
+    /// Templates pulled in through `extends`, `include`, `import` and `from`, or `None` if any of them can only be known at render time.
+    references: Option<Vec<String>>,
+    /// The template it extends, if known.
+    parent: Option<String>,
 }
 
 impl JinjaEnvironment {
     pub fn new() -> Self {
+        let templates = Arc::new(RwLock::new(HashMap::new()));
+        let assets = Arc::new(RwLock::new(None));
+
         Self {
-            templates: RwLock::new(HashMap::new()),
+            env: RwLock::new(Arc::new(Self::make_env(templates.clone(), assets.clone()))),
+            templates,
+            registered: RwLock::new(HashSet::new()),
+            meta: RwLock::new(HashMap::new()),
+            deps: RwLock::new(HashMap::new()),
+            assets,
+            built: RwLock::new(Utc::now()),
+            data: RwLock::new(minijinja::Value::UNDEFINED),
+            filters: Arc::new(RwLock::new(HashMap::new())),
+            functions: Arc::new(RwLock::new(HashMap::new())),
+            stand_ins: RwLock::new(HashSet::new()),
         }
     }
 
-    fn make_env(templates: HashMap<String, String>) -> Environment<'static> {
+    fn make_env(
+        templates: Arc<RwLock<HashMap<String, String>>>,
+        assets: Arc<RwLock<Option<Assets>>>,
+    ) -> Environment<'static> {
         let mut base = Environment::new();
 
+        // Keeps template sources around for error messages, even in release builds.
+        base.set_debug(true);
+
         base.add_filter("required", required_filter);
+        filters::register(&mut base);
+        base.add_function("asset", move |name: &str| {
+            asset_function(assets.read().unwrap().as_deref(), name)
+        });
 
-        base.set_loader(move |name| Ok(templates.get(name).map(String::to_string)));
+        // Templates are compiled the first time they're needed, and stay compiled until `register` sees them change.
+        base.set_loader(move |name| Ok(templates.read().unwrap().get(name).cloned()));
 
         base
     }
 
+    fn update(&self, f: impl FnOnce(&mut Environment<'static>)) {
+        f(Arc::make_mut(&mut self.env.write().unwrap()));
+    }
+
+    /// Forgets what the previous build registered from Lua and the assets it found, keeping compiled templates around.
+    pub fn reset(&self) {
+        self.filters.write().unwrap().clear();
+        self.functions.write().unwrap().clear();
+
+        *self.assets.write().unwrap() = None;
+        *self.built.write().unwrap() = Utc::now();
+        self.registered.write().unwrap().clear();
+    }
+
     pub fn register(&self, path: &Path) -> eyre::Result<()> {
         let name = path.template_name()?;
         let source = fs::read_to_string(path)?;
-        self.templates.write().unwrap().insert(name, source);
+
+        let doc = frontmatter::split(&source)
+            .map_err(|err| eyre!("{}: bad front matter: {}", path.display_simple(), err))?;
+
+        // Keep line numbers intact for error reporting by commenting the front matter out.
+        let source = match doc.meta {
+            Some(meta) => {
+                self.meta.write().unwrap().insert(name.clone(), meta);
+                format!("{{#{}#}}{}", "\n".repeat(doc.lines), doc.body)
+            }
+            None => {
+                self.meta.write().unwrap().remove(&name);
+                source
+            }
+        };
+
+        let deps = Deps {
+            path: path.to_path_buf(),
+            references: references(&source),
+            parent: parent(&source),
+        };
+        self.deps.write().unwrap().insert(name.clone(), deps);
+        self.registered.write().unwrap().insert(name.clone());
+
+        let mut templates = self.templates.write().unwrap();
+        if templates.get(&name) != Some(&source) {
+            templates.insert(name.clone(), source);
+            drop(templates);
+            self.update(|env| env.remove_template(&name));
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the templates which weren't registered during this build, i.e. were deleted.
+    pub fn prune(&self) {
+        let registered = self.registered.read().unwrap();
+        let gone: Vec<_> = (self.templates.read().unwrap().keys())
+            .filter(|x| !registered.contains(*x))
+            .cloned()
+            .collect();
+
+        for name in &gone {
+            self.templates.write().unwrap().remove(name);
+            self.meta.write().unwrap().remove(name);
+            self.deps.write().unwrap().remove(name);
+        }
+        self.update(|env| gone.iter().for_each(|x| env.remove_template(x)));
+    }
+
+    /// Returns the source files of a template and of everything it references, transitively.
+    ///
+    /// Returns `None` if that can't be known without rendering the template.
+    pub fn dependencies(&self, name: &str) -> Option<HashSet<PathBuf>> {
+        let deps = self.deps.read().unwrap();
+
+        let mut files = HashSet::new();
+        let mut seen = HashSet::new();
+        let mut queue = vec![name.to_string()];
+
+        while let Some(name) = queue.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            // Missing templates either fail the render or are ignored by it.
+            let Some(template) = deps.get(&name) else {
+                continue;
+            };
+
+            files.insert(template.path.clone());
+            queue.extend(template.references.clone()?);
+        }
+
+        Some(files)
+    }
+
+    /// Returns a template and the ones it extends, in that order.
+    pub fn ancestry(&self, name: &str) -> Vec<String> {
+        let deps = self.deps.read().unwrap();
+        let mut names = vec![name.to_string()];
+
+        while let Some(parent) = deps
+            .get(names.last().unwrap())
+            .and_then(|x| x.parent.clone())
+        {
+            if names.contains(&parent) {
+                break;
+            }
+            names.push(parent);
+        }
+
+        names
+    }
+
+    /// Returns a template's front matter, if it has any.
+    pub fn meta(&self, name: &str) -> Option<minijinja::Value> {
+        self.meta.read().unwrap().get(name).cloned()
+    }
+
+    pub fn set_data(&self, data: minijinja::Value) {
+        *self.data.write().unwrap() = data;
+    }
+
+    pub fn set_assets(&self, assets: HashMap<String, String>) {
+        *self.assets.write().unwrap() = Some(Arc::new(assets));
+    }
+
+    /// Makes a filter available to templates, replacing any previous one with the same name. Built-in filters can't be replaced.
+    pub fn add_filter(&self, name: String, filter: Callable) -> eyre::Result<()> {
+        let key = format!("filter:{}", name);
+        if !self.stand_ins.read().unwrap().contains(&key) {
+            validate_name(&name)?;
+
+            let env = self.env.read().unwrap().clone();
+            let probe = env.render_str(&format!("{{{{ none | {} }}}}", name), ());
+            if !probe.is_err_and(|x| x.kind() == ErrorKind::UnknownFilter) {
+                return Err(eyre!(
+                    "`{}` is a built-in filter and can't be replaced",
+                    name
+                ));
+            }
+
+            let stand_in = stand_in(&self.filters, &name, ErrorKind::UnknownFilter);
+            self.update(|env| env.add_filter(name.clone(), stand_in));
+            self.stand_ins.write().unwrap().insert(key);
+        }
+
+        self.filters.write().unwrap().insert(name, filter);
+        Ok(())
+    }
+
+    /// Makes a function available to templates, replacing any previous one with the same name. Built-in functions can't be replaced.
+    pub fn add_function(&self, name: String, function: Callable) -> eyre::Result<()> {
+        let key = format!("function:{}", name);
+        if !self.stand_ins.read().unwrap().contains(&key) {
+            validate_name(&name)?;
+
+            let env = self.env.read().unwrap().clone();
+            if env.globals().any(|(x, _)| x == name) {
+                return Err(eyre!(
+                    "`{}` is a built-in function and can't be replaced",
+                    name
+                ));
+            }
+
+            let stand_in = stand_in(&self.functions, &name, ErrorKind::UnknownFunction);
+            self.update(|env| env.add_function(name.clone(), stand_in));
+            self.stand_ins.write().unwrap().insert(key);
+        }
+
+        self.functions.write().unwrap().insert(name, function);
         Ok(())
     }
 
@@ -43,34 +279,153 @@ impl JinjaEnvironment {
         templates.keys().map(String::to_string).collect()
     }
 
-    pub fn render(
+    /// Renders a template without writing it anywhere.
+    ///
+    /// `target` is where the result is headed inside `dist`, if anywhere, and `source` is the file the render originates from.
+    pub fn render_string(
         &self,
         name: &str,
-        target: &Path,
         context: &minijinja::Value,
-    ) -> eyre::Result<()> {
-        let parent = target.parent().ok_or(eyre!("No parent directory???"))?;
-        let _ = fs::create_dir_all(parent);
+        target: Option<&Path>,
+        source: &Path,
+    ) -> eyre::Result<String> {
+        let config = crate::config();
+
+        let mut facts = context! {
+            template => name,
+            source => source.www_name().ok(),
+            built => self.built.read().unwrap().to_rfc3339_opts(SecondsFormat::Secs, true),
+        };
+        if let Some(target) = target {
+            let target = context! {
+                path => target.dist_name()?,
+                url => paths::url(target)?,
+            };
+            facts = merge_maps([facts, target]);
+        }
+
+        // The front matter and the context's own `page` come last, so they can override anything.
+        let page = merge_maps([
+            facts,
+            self.meta(name).unwrap_or_default(),
+            context.get_attr("page").unwrap_or_default(),
+        ]);
 
         let context = merge_maps([
             context! {
-                __prod => crate::args().prod()
+                __prod => crate::args().prod(),
+                __base_url => config.base_url,
+                data => self.data.read().unwrap().clone(),
             },
+            minijinja::Value::from_serialize(&config.globals),
             context.clone(),
+            context! { page },
         ]);
 
-        let templates = self.templates.read().unwrap().to_owned();
-        let base = Self::make_env(templates);
+        let base = self.env.read().unwrap().clone();
+
+        let result = base.get_template(name).and_then(|x| x.render(context));
+        result.map_err(|err| TemplateError::new(&err, self.ancestry(name)).into())
+    }
+}
 
-        let data = base.get_template(name)?.render(context)?;
+fn validate_name(name: &str) -> eyre::Result<()> {
+    let valid = name.chars().all(|x| x.is_ascii_alphanumeric() || x == '_')
+        && name.starts_with(|x: char| !x.is_ascii_digit());
 
-        if let Some("html") = target.extension_str() {
-            minify::write(&target, minify::Type::Html, data)?;
-        } else {
-            fs::write(target, data)?;
+    match valid {
+        true => Ok(()),
+        false => Err(eyre!("`{}` isn't a valid name for templates", name)),
+    }
+}
+
+/// Calls whatever is registered under a name during the current build.
+fn stand_in(
+    callables: &Callables,
+    name: &str,
+    missing: ErrorKind,
+) -> impl Fn(Rest<minijinja::Value>) -> Result<minijinja::Value, Error> + Send + Sync + 'static {
+    let callables = callables.clone();
+    let name = name.to_string();
+
+    move |args| {
+        let callable = callables.read().unwrap().get(&name).cloned();
+        match callable {
+            Some(callable) => callable(&args),
+            None => Err(Error::new(
+                missing,
+                format!("`{}` isn't registered anymore", name),
+            )),
         }
+    }
+}
 
-        Ok(())
+fn references(source: &str) -> Option<Vec<String>> {
+    let mut names = Vec::new();
+
+    for tag in tags(source) {
+        let keyword = tag.split_whitespace().next().unwrap_or_default();
+        if !matches!(keyword, "extends" | "include" | "import" | "from") {
+            continue;
+        }
+
+        let literals = string_literals(tag);
+        if literals.is_empty() {
+            return None;
+        }
+        names.extend(literals);
+    }
+
+    Some(names)
+}
+
+/// The template named by the `extends` tag, if there is one and it isn't computed at render time.
+fn parent(source: &str) -> Option<String> {
+    let tag = tags(source).find(|x| x.split_whitespace().next() == Some("extends"))?;
+    string_literals(tag).into_iter().next()
+}
+
+/// Contents of every `{% ... %}` tag, without whitespace control.
+fn tags(source: &str) -> impl Iterator<Item = &str> {
+    let mut rest = source;
+
+    std::iter::from_fn(move || {
+        let start = rest.find("{%")?;
+        rest = &rest[start + 2..];
+        let end = rest.find("%}").unwrap_or(rest.len());
+        let tag = rest[..end].trim_start_matches(['-', '+']).trim_start();
+        rest = &rest[end..];
+        Some(tag)
+    })
+}
+
+fn string_literals(tag: &str) -> Vec<String> {
+    let mut literals = Vec::new();
+    let mut chars = tag.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '"' || c == '\'' {
+            literals.push(chars.by_ref().take_while(|&x| x != c).collect());
+        }
+    }
+
+    literals
+}
+
+fn asset_function(assets: Option<&HashMap<String, String>>, name: &str) -> Result<String, Error> {
+    let Some(assets) = assets else {
+        return Err(Error::new(
+            ErrorKind::InvalidOperation,
+            "asset() is unavailable until every file has been processed, e.g. inside render_string()",
+        ));
+    };
+
+    match assets.get(name.trim_start_matches('/')) {
+        Some(url) => Ok(format!("/{}", url)),
+        None => Err(Error::new(
+            ErrorKind::InvalidOperation,
+            format!("no such asset: {}", name),
+        )),
     }
 }
 
@@ -1,24 +1,43 @@
-use std::fs::{self, File};
+use std::{fs, path::Path, sync::Arc, time::UNIX_EPOCH};
 
 use chrono::{DateTime, Utc};
-use color_eyre::eyre;
-use minijinja::Value as JValue;
+use color_eyre::eyre::{self, eyre};
+use minijinja::{Value as JValue, context, value::merge_maps};
 use mlua::{Lua, LuaSerdeExt, Value};
+use serde::{Deserialize, Serialize};
 
 use sanity_macros::luafn;
 
 use crate::{
-    lua::{LuaFn, Render, State},
-    paths,
+    fs::PathExt,
+    jinja2::Callable,
+    lua::{Hook, LuaFn, Output, Render, State, sandbox},
+    paths::{self, PathExt as _},
 };
 
 pub fn all() -> Vec<Box<dyn LuaFn + Send>> {
     vec![
         Box::new(render),
+        Box::new(paginate),
+        Box::new(render_string),
         Box::new(json),
+        Box::new(toml),
+        Box::new(yaml),
+        Box::new(csv),
         Box::new(read),
         Box::new(lastmod),
         Box::new(inject),
+        Box::new(markdown),
+        Box::new(frontmatter),
+        Box::new(pages),
+        Box::new(feed),
+        Box::new(write),
+        Box::new(write_json),
+        Box::new(on_output),
+        Box::new(filter),
+        Box::new(template_function),
+        Box::new(ls),
+        Box::new(glob),
     ]
 }
 
@@ -28,29 +47,166 @@ pub fn render(lua: &Lua, template: String, target: String, context: Value) -> ey
     trace!("lua render: {} {} => {:?}", template, target, context);
 
     let mut state = lua.app_data_mut::<State>().unwrap();
+    let source = state.script.clone();
     state.render_queue.push(Render {
         context: JValue::from_serialize(context),
-        target: paths::dist()?.join(target),
+        target: paths::pretty(sandbox::confine(&paths::dist()?, &target)?),
         template,
+        source,
     });
 
     Ok(Value::Nil)
 }
 
+/// Queues a template to be rendered once per page of items, with a `pagination` object describing each page.
+///
+/// Options are `path` (required, with `{n}` standing for the page number), `per_page` (10 by default), `first` (a different path for the first page), and `context` (extra variables for every page).
+#[luafn]
+pub fn paginate(
+    lua: &Lua,
+    template: String,
+    items: mlua::Table,
+    options: Value,
+) -> eyre::Result<Value> {
+    let options: PaginateOptions = lua.from_value(options)?;
+    if !options.path.contains("{n}") {
+        return Err(eyre!(
+            "pagination path must contain `{{n}}`: {}",
+            options.path
+        ));
+    }
+    if options.per_page == 0 {
+        return Err(eyre!("`per_page` must be positive"));
+    }
+
+    let items = items
+        .sequence_values::<Value>()
+        .map(|x| Ok(JValue::from_serialize(x?)))
+        .collect::<mlua::Result<Vec<_>>>()?;
+
+    // An empty list still gets a page, e.g. a blog index without posts.
+    let mut chunks: Vec<_> = items.chunks(options.per_page).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+    let count = chunks.len();
+    let path = |n: usize| match (n, &options.first) {
+        (1, Some(first)) => first.clone(),
+        _ => options.path.replace("{n}", &n.to_string()),
+    };
+    let targets = (1..=count)
+        .map(|n| Ok(paths::pretty(sandbox::confine(&paths::dist()?, &path(n))?)))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let urls = (targets.iter())
+        .map(|x| paths::url(x))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    let url = |n: usize| urls[n - 1].clone();
+
+    let mut state = lua.app_data_mut::<State>().unwrap();
+    let source = state.script.clone();
+
+    for (i, (chunk, target)) in chunks.iter().zip(targets).enumerate() {
+        let n = i + 1;
+        let pagination = context! {
+            page => n,
+            pages => count,
+            per_page => options.per_page,
+            total => items.len(),
+            items => chunk,
+            url => url(n),
+            first => url(1),
+            last => url(count),
+            prev => (n > 1).then(|| url(n - 1)),
+            next => (n < count).then(|| url(n + 1)),
+        };
+
+        state.render_queue.push(Render {
+            context: merge_maps([options.context.clone(), context! { pagination }]),
+            target,
+            template: template.clone(),
+            source: source.clone(),
+        });
+    }
+
+    Ok(Value::Nil)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PaginateOptions {
+    path: String,
+    #[serde(default = "default_per_page")]
+    per_page: usize,
+    first: Option<String>,
+    #[serde(default)]
+    context: JValue,
+}
+
+fn default_per_page() -> usize {
+    10
+}
+
+/// Renders a template right away and returns the result.
+///
+/// Every template inside `www` can be rendered, as templates are registered before any script runs.
+/// Globals added with `inject` so far are available; `asset()` isn't, since assets are only known once every file has been processed.
+#[luafn]
+pub fn render_string(lua: &Lua, template: String, context: Value) -> eyre::Result<String> {
+    let (jinja, globals, source) = {
+        let state = lua.app_data_ref::<State>().unwrap();
+        let globals = JValue::from_serialize(&state.global_context);
+        (state.jinja.clone(), globals, state.script.clone())
+    };
+
+    for path in jinja.dependencies(&template).into_iter().flatten() {
+        track(lua, &path);
+    }
+
+    let context = merge_maps([globals, JValue::from_serialize(context)]);
+    jinja.render_string(&template, &context, None, &source)
+}
+
 /// Loads a JSON file from `www` as a Lua table.
 #[luafn]
 pub fn json(lua: &Lua, path: String) -> eyre::Result<Value> {
-    let path = paths::www()?.join(path);
-    let file = File::open(path)?;
-    let serde: JValue = serde_json::from_reader(file)?; // INSANE hack
-    Ok(lua.to_value(&serde)?)
+    load(lua, &path, crate::data::json)
+}
+
+/// Loads a TOML file from `www` as a Lua table. Datetimes are turned into strings.
+#[luafn]
+pub fn toml(lua: &Lua, path: String) -> eyre::Result<Value> {
+    load(lua, &path, crate::data::toml)
+}
+
+/// Loads a YAML file from `www` as a Lua table.
+#[luafn]
+pub fn yaml(lua: &Lua, path: String) -> eyre::Result<Value> {
+    load(lua, &path, crate::data::yaml)
+}
+
+/// Loads a CSV file from `www` as an array of rows.
+///
+/// Rows are tables keyed by the header row, or plain arrays with `{ header = false }`.
+/// Pass `{ coerce = true }` to turn numeric and `true`/`false` cells into numbers and booleans, and `{ delimiter = ";" }` for other separators.
+#[luafn]
+pub fn csv(lua: &Lua, path: String, options: Value) -> eyre::Result<Value> {
+    let options: crate::data::CsvOptions = match options {
+        Value::Nil => Default::default(),
+        options => lua.from_value(options)?,
+    };
+
+    load(lua, &path, |source| crate::data::csv(source, &options))
 }
 
 /// Reads a text file from `www` and returns its contents as a string.
+///
+/// Front matter, if present, is stripped. Use `frontmatter` to read it.
 #[luafn]
 pub fn read(lua: &Lua, path: String) -> eyre::Result<String> {
-    let path = paths::www()?.join(path);
-    Ok(fs::read_to_string(&path)?)
+    let path = sandbox::confine(&paths::www()?, &path)?;
+    track(lua, &path);
+    let source = fs::read_to_string(&path)?;
+    Ok(crate::frontmatter::split(&source)?.body.to_string())
 }
 
 /// Returns an input-file's last-modified date in UTC as an ISO timestamp string.
@@ -58,7 +214,8 @@ pub fn read(lua: &Lua, path: String) -> eyre::Result<String> {
 /// Useful for embedding inside a `sitemap.xml`.
 #[luafn]
 pub fn lastmod(lua: &Lua, path: String) -> eyre::Result<String> {
-    let path = paths::www()?.join(path);
+    let path = sandbox::confine(&paths::www()?, &path)?;
+    track(lua, &path);
     let modif = fs::metadata(&path).and_then(|x| x.modified())?;
     let iso: DateTime<Utc> = modif.into();
     Ok(iso.format("%+").to_string())
@@ -71,3 +228,403 @@ pub fn inject(lua: &Lua, name: String, value: Value) -> eyre::Result<Value> {
     ctx.insert(name, JValue::from_serialize(value));
     Ok(Value::Nil)
 }
+
+/// Renders a Markdown string to HTML.
+///
+/// Use the `safe` filter to output the result from a template unescaped.
+#[luafn]
+pub fn markdown(lua: &Lua, text: String) -> eyre::Result<String> {
+    Ok(crate::markdown::render(&text))
+}
+
+/// Returns a text file's YAML/TOML front matter as a Lua table, or `nil` if it has none.
+#[luafn]
+pub fn frontmatter(lua: &Lua, path: String) -> eyre::Result<Value> {
+    let path = sandbox::confine(&paths::www()?, &path)?;
+    track(lua, &path);
+    let source = fs::read_to_string(&path)?;
+
+    match crate::frontmatter::split(&source)?.meta {
+        Some(meta) => Ok(lua.to_value(&meta)?),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// Lists templates, Markdown, HTML and text files with front matter inside a `www` directory, recursively.
+///
+/// Returns an array of `{ path = ..., meta = ... }` tables sorted by path, with paths relative to `www`.
+#[luafn]
+pub fn pages(lua: &Lua, dir: String) -> eyre::Result<Value> {
+    let mut found = Vec::new();
+    collect_pages(lua, &sandbox::confine(&paths::www()?, &dir)?, &mut found)?;
+    found.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let list = lua.create_table()?;
+    for (path, meta) in found {
+        let entry = lua.create_table()?;
+        entry.set("path", path)?;
+        entry.set("meta", lua.to_value(&meta)?)?;
+        list.push(entry)?;
+    }
+
+    Ok(Value::Table(list))
+}
+
+/// Writes an Atom feed (or RSS 2.0 with `format = "rss"`) into dist.
+///
+/// Takes a table with `title`, `path` (relative to dist), and optional `format`, `description`, `link`, `author` fields, plus an `entries` array.
+/// Each entry has `title`, `link`, and optional `date`, `updated`, `id`, `summary`, `content` (HTML), `author` fields.
+/// Dates can be Unix timestamps or strings in any common format. Relative links are prefixed with `base_url`.
+/// Atom feeds need an `author`, either on the feed or on every entry.
+#[luafn]
+pub fn feed(lua: &Lua, options: Value) -> eyre::Result<Value> {
+    let options: crate::feed::Feed = lua.from_value(options)?;
+    let contents = options.to_xml()?.into_bytes();
+
+    queue_output(lua, &options.path, contents)?;
+    Ok(Value::Nil)
+}
+
+/// Writes a string to a file inside dist. HTML and JS get minified in production builds.
+#[luafn]
+pub fn write(lua: &Lua, target: String, contents: String) -> eyre::Result<Value> {
+    queue_output(lua, &target, contents.into_bytes())?;
+    Ok(Value::Nil)
+}
+
+/// Writes a Lua value to a file inside dist as JSON. Pass `{ pretty = true }` to indent it.
+#[luafn]
+pub fn write_json(lua: &Lua, target: String, value: Value, options: Value) -> eyre::Result<Value> {
+    let options: JsonOptions = match options {
+        Value::Nil => Default::default(),
+        options => lua.from_value(options)?,
+    };
+
+    let contents = if options.pretty {
+        serde_json::to_vec_pretty(&value)?
+    } else {
+        serde_json::to_vec(&value)?
+    };
+
+    queue_output(lua, &target, contents)?;
+    Ok(Value::Nil)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct JsonOptions {
+    pretty: bool,
+}
+
+/// Registers a function to post-process outputs whose path relative to dist matches a glob pattern, e.g. `**/*.html`.
+///
+/// It's called with the path and contents of every matching output before minification, and whatever string it returns replaces the contents.
+/// Returning `nil` leaves the output as it is.
+#[luafn]
+pub fn on_output(lua: &Lua, pattern: String, callback: mlua::Function) -> eyre::Result<Value> {
+    let pattern = ::glob::Pattern::new(pattern.trim_start_matches('/'))?;
+
+    let mut state = lua.app_data_mut::<State>().unwrap();
+    let source = state.script.clone();
+    state.hooks.push(Hook {
+        pattern,
+        callback,
+        source,
+    });
+
+    Ok(Value::Nil)
+}
+
+/// Makes a Lua function available as a filter inside templates, e.g. `{{ post.date | nice_date }}`.
+///
+/// It's called with the filtered value followed by the filter's arguments, and whatever it returns is passed on to the template.
+/// Strings it returns are escaped like any other unless `safe` is set in the options.
+#[luafn]
+pub fn filter(
+    lua: &Lua,
+    name: String,
+    callback: mlua::Function,
+    options: Value,
+) -> eyre::Result<Value> {
+    let options = callable_options(lua, options)?;
+    let callable = callable(lua, "filter", &name, callback, options.safe)?;
+    let jinja = lua.app_data_ref::<State>().unwrap().jinja.clone();
+    jinja.add_filter(name, callable)?;
+    Ok(Value::Nil)
+}
+
+/// Makes a Lua function available as a function inside templates, e.g. `{{ greet("world") }}`.
+///
+/// Takes the same options as `filter`.
+#[luafn]
+pub fn template_function(
+    lua: &Lua,
+    name: String,
+    callback: mlua::Function,
+    options: Value,
+) -> eyre::Result<Value> {
+    let options = callable_options(lua, options)?;
+    let callable = callable(lua, "function", &name, callback, options.safe)?;
+    let jinja = lua.app_data_ref::<State>().unwrap().jinja.clone();
+    jinja.add_function(name, callable)?;
+    Ok(Value::Nil)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct CallableOptions {
+    /// Mark returned strings as safe, so that markup isn't escaped.
+    safe: bool,
+}
+
+fn callable_options(lua: &Lua, options: Value) -> eyre::Result<CallableOptions> {
+    Ok(match options {
+        Value::Nil => Default::default(),
+        options => lua.from_value(options)?,
+    })
+}
+
+/// Wraps a Lua function to be called from templates, converting values on the way in and out.
+fn callable(
+    lua: &Lua,
+    kind: &'static str,
+    name: &str,
+    callback: mlua::Function,
+    safe: bool,
+) -> eyre::Result<Callable> {
+    let mut state = lua.app_data_mut::<State>().unwrap();
+    let source = state.script.clone();
+    state.extensions.insert(source.clone());
+
+    let name = name.to_string();
+    // The environment holding this lives inside the Lua state, so a strong reference would never be dropped.
+    let lua = lua.weak();
+    Ok(Arc::new(move |args: &[JValue]| {
+        let error = |err: mlua::Error| {
+            minijinja::Error::new(
+                minijinja::ErrorKind::InvalidOperation,
+                format!(
+                    "{} `{}` from {} failed: {}",
+                    kind,
+                    name,
+                    source.display_simple(),
+                    err
+                ),
+            )
+        };
+
+        let lua = lua
+            .try_upgrade()
+            .ok_or_else(|| error(mlua::Error::UserDataDestructed))?;
+        let args = args
+            .iter()
+            .map(|x| lua.to_value(x))
+            .collect::<mlua::Result<mlua::MultiValue>>()
+            .map_err(error)?;
+        let result = callback.call::<Value>(args).map_err(error)?;
+
+        Ok(match result {
+            Value::String(x) if safe => JValue::from_safe_string(x.to_string_lossy()),
+            result => JValue::from_serialize(&result),
+        })
+    }))
+}
+
+/// Lists the files and directories directly inside a `www` directory.
+///
+/// Returns an array of `{ path, name, ext, dir, size, mtime }` tables sorted by path, with paths relative to `www` and `mtime` as a Unix timestamp.
+/// Pass `{ underscored = false }` to leave out `_`-prefixed entries.
+#[luafn]
+pub fn ls(lua: &Lua, dir: String, options: Value) -> eyre::Result<Value> {
+    let options = ListOptions::from_lua(lua, options)?;
+
+    let mut found = Vec::new();
+    for child in fs::read_dir(sandbox::confine(&paths::www()?, &dir)?)? {
+        let child = child?.path();
+        if !sandbox::within(&paths::www()?, &child) {
+            continue;
+        }
+        if options.underscored || !child.is_underscored() {
+            found.push(Listing::new(lua, &child)?);
+        }
+    }
+
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(lua.to_value(&found)?)
+}
+
+/// Finds the files inside `www` matching a glob pattern, e.g. `blog/*.md` or `**/*.png`.
+///
+/// Returns the same tables as `ls`, sorted by path. Pass `{ underscored = false }` to leave out `_`-prefixed files.
+#[luafn]
+pub fn glob(lua: &Lua, pattern: String, options: Value) -> eyre::Result<Value> {
+    let options = ListOptions::from_lua(lua, options)?;
+    let pattern = pattern.trim_start_matches('/');
+
+    // Only walk the part of the tree the pattern can possibly match.
+    let base: Vec<_> = pattern
+        .split('/')
+        .take_while(|x| !x.contains(['*', '?', '[']))
+        .collect();
+    let base = sandbox::confine(&paths::www()?, &base.join("/"))?;
+    let pattern = ::glob::Pattern::new(pattern)?;
+
+    let mut found = Vec::new();
+    if base.is_dir() {
+        collect_glob(lua, &base, &pattern, &options, &mut found)?;
+    } else if base.is_file() && pattern.matches(&base.www_name()?) {
+        found.push(Listing::new(lua, &base)?);
+    }
+
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(lua.to_value(&found)?)
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ListOptions {
+    /// Include `_`-prefixed entries.
+    underscored: bool,
+}
+
+impl Default for ListOptions {
+    fn default() -> Self {
+        Self { underscored: true }
+    }
+}
+
+impl ListOptions {
+    fn from_lua(lua: &Lua, value: Value) -> eyre::Result<Self> {
+        match value {
+            Value::Nil => Ok(Self::default()),
+            value => Ok(lua.from_value(value)?),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Listing {
+    path: String,
+    name: String,
+    ext: String,
+    dir: bool,
+    size: u64,
+    mtime: u64,
+}
+
+impl Listing {
+    fn new(lua: &Lua, path: &Path) -> eyre::Result<Self> {
+        let meta = fs::metadata(path)?;
+
+        if meta.is_file() {
+            track(lua, path);
+        }
+
+        Ok(Self {
+            path: path.www_name()?,
+            name: path
+                .file_name()
+                .map(|x| x.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            ext: path.extension_str().unwrap_or_default().to_string(),
+            dir: meta.is_dir(),
+            size: meta.len(),
+            mtime: meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs(),
+        })
+    }
+}
+
+fn collect_glob(
+    lua: &Lua,
+    dir: &Path,
+    pattern: &::glob::Pattern,
+    options: &ListOptions,
+    found: &mut Vec<Listing>,
+) -> eyre::Result<()> {
+    let match_options = ::glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    for child in fs::read_dir(dir)? {
+        let child = child?.path();
+
+        if !options.underscored && child.is_underscored() {
+            continue;
+        }
+        if !sandbox::within(&paths::www()?, &child) {
+            continue;
+        }
+
+        if child.is_dir() {
+            collect_glob(lua, &child, pattern, options, found)?;
+        } else if pattern.matches_with(&child.www_name()?, match_options) {
+            found.push(Listing::new(lua, &child)?);
+        }
+    }
+
+    Ok(())
+}
+
+fn collect_pages(lua: &Lua, dir: &Path, found: &mut Vec<(String, JValue)>) -> eyre::Result<()> {
+    for child in fs::read_dir(dir)? {
+        let child = child?.path();
+
+        if !sandbox::within(&paths::www()?, &child) {
+            continue;
+        }
+
+        if child.is_dir() {
+            collect_pages(lua, &child, found)?;
+            continue;
+        }
+
+        if !matches!(child.extension_str(), Some("j2" | "md" | "html" | "txt")) {
+            continue;
+        }
+
+        track(lua, &child);
+        let source = fs::read_to_string(&child)?;
+        if let Some(meta) = crate::frontmatter::split(&source)?.meta {
+            found.push((child.www_name()?, meta));
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a data file from `www` and converts it to a Lua value, naming the file in parse errors.
+fn load(
+    lua: &Lua,
+    path: &str,
+    parse: impl FnOnce(&str) -> eyre::Result<JValue>,
+) -> eyre::Result<Value> {
+    let full = sandbox::confine(&paths::www()?, path)?;
+    track(lua, &full);
+    let source = fs::read_to_string(&full).map_err(|err| eyre!("{}: {}", path, err))?;
+    let value = parse(&source).map_err(|err| eyre!("{}: {}", path, err))?;
+    Ok(lua.to_value(&value)?)
+}
+
+/// Queues a file to be written into dist once every script has run.
+fn queue_output(lua: &Lua, target: &str, contents: Vec<u8>) -> eyre::Result<()> {
+    let mut state = lua.app_data_mut::<State>().unwrap();
+    let source = state.script.clone();
+    state.outputs.push(Output {
+        target: paths::pretty(sandbox::confine(&paths::dist()?, target)?),
+        contents,
+        source,
+    });
+    Ok(())
+}
+
+/// Remembers that the running script depends on a file, for incremental rebuilds.
+pub(super) fn track(lua: &Lua, path: &Path) {
+    let Ok(path) = path.canonicalize() else {
+        return;
+    };
+
+    let mut state = lua.app_data_mut::<State>().unwrap();
+    let script = state.script.clone();
+    state.reads.entry(script).or_default().insert(path);
+}
@@ -1,22 +1,47 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::Write,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
-use color_eyre::eyre;
+use color_eyre::eyre::{self, eyre};
 use minijinja::Value as JValue;
-use mlua::{IntoLua, Lua, Value};
+use mlua::{IntoLua, Lua, LuaSerdeExt, Value};
 
-use crate::paths;
+use crate::{
+    jinja2::JinjaEnvironment,
+    paths::{self, PathExt as _},
+};
 
 mod fns;
+mod modules;
+pub mod sandbox;
 
 pub struct Render {
     pub template: String,
     pub target: PathBuf,
     pub context: JValue,
+    /// The file this render originates from, e.g. the Lua script that queued it.
+    pub source: PathBuf,
+}
+
+/// A file written into `dist` straight from Lua.
+pub struct Output {
+    pub target: PathBuf,
+    pub contents: Vec<u8>,
+    /// The script that produced it.
+    pub source: PathBuf,
+}
+
+/// A callback registered with `on_output`.
+pub struct Hook {
+    /// Glob pattern matched against output paths relative to `dist`.
+    pub pattern: glob::Pattern,
+    pub callback: mlua::Function,
+    /// The script that registered it.
+    pub source: PathBuf,
 }
 
 pub struct Shebang {
@@ -24,30 +49,171 @@ pub struct Shebang {
 }
 
 impl Shebang {
-    pub fn try_new() -> eyre::Result<Self> {
-        try_new_shebang()
+    pub fn try_new(jinja: Arc<JinjaEnvironment>) -> eyre::Result<Self> {
+        try_new_shebang(jinja)
     }
 
     pub fn process(&self, file: &Path) -> eyre::Result<()> {
+        // Kept on the first line so as not to throw off line numbers in error messages.
         let contents = format!(
-            r#"_G.__sanity_file = ...
-            {}"#,
+            "_G.__sanity_file = ...; {}",
             String::from_utf8(std::fs::read(file)?)?
         );
 
+        if let Some(mut state) = self.lua.app_data_mut::<State>() {
+            state.script = file.to_path_buf();
+        }
+
         let args = (file.to_string_lossy(),);
-        self.lua.load(contents).call::<()>(args)?;
+        self.lua
+            .load(contents)
+            .set_name(format!("@{}", file.display_simple()))
+            .call::<()>(args)?;
+        Ok(())
+    }
+
+    /// Exposes the contents of the data directory to scripts as the `data` table.
+    ///
+    /// Every field read from it is a fresh copy, so that scripts can't change what templates and other scripts see.
+    pub fn set_data(&self, data: &JValue) -> eyre::Result<()> {
+        let lua = &self.lua;
+        let meta = lua.create_table()?;
+
+        let fields = data.clone();
+        meta.set(
+            "__index",
+            lua.create_function(move |lua, (_, key): (Value, String)| {
+                match fields.get_attr(&key) {
+                    Ok(field) if !field.is_undefined() => lua.to_value(&field),
+                    _ => Ok(Value::Nil),
+                }
+            })?,
+        )?;
+
+        meta.set(
+            "__newindex",
+            lua.create_function(|_, _: mlua::MultiValue| -> mlua::Result<()> {
+                Err(mlua::Error::runtime("`data` is read-only"))
+            })?,
+        )?;
+
+        let all = data.clone();
+        let copy = lua.create_function(move |lua, ()| lua.to_value(&all))?;
+
+        let proxy = lua.create_table()?;
+        proxy.set_metatable(Some(meta))?;
+
+        // LuaJIT ignores `__pairs`, so `pairs` learns about the proxy directly. `data` lives in the metatable of the
+        // globals, which is the only way to catch a script assigning to it: the scripts all share one state.
+        lua.load(
+            r#"
+            local proxy, copy = ...
+            local pairs, next, rawequal, rawset = pairs, next, rawequal, rawset
+
+            _G.pairs = function(t)
+                if rawequal(t, proxy) then
+                    return next, copy(), nil
+                end
+                return pairs(t)
+            end
+
+            setmetatable(_G, {
+                __index = function(_, key)
+                    if key == "data" then
+                        return proxy
+                    end
+                end,
+                __newindex = function(t, key, value)
+                    if key == "data" then
+                        error("`data` is read-only; use a local variable instead", 2)
+                    end
+                    rawset(t, key, value)
+                end,
+            })
+            "#,
+        )
+        .set_name("=data")
+        .call::<()>((proxy, copy))?;
         Ok(())
     }
 
+    /// Takes everything the scripts have done so far, leaving a blank state for `on_output` hooks to run with.
     pub fn state(&self) -> State {
-        self.lua.remove_app_data().unwrap()
+        let mut state = self.lua.app_data_mut::<State>().unwrap();
+        let blank = State::new(state.jinja.clone());
+        std::mem::replace(&mut *state, blank)
     }
 }
 
 pub struct State {
     pub render_queue: Vec<Render>,
+    pub outputs: Vec<Output>,
     pub global_context: HashMap<String, JValue>,
+    /// The script currently being run.
+    pub script: PathBuf,
+    /// Files read by each script.
+    pub reads: HashMap<PathBuf, HashSet<PathBuf>>,
+    /// Modules being loaded through `require`, innermost last.
+    pub requiring: Vec<PathBuf>,
+    pub hooks: Vec<Hook>,
+    /// Scripts which registered template filters or functions.
+    pub extensions: HashSet<PathBuf>,
+    /// Templates for `render_string` to render.
+    pub jinja: Arc<JinjaEnvironment>,
+}
+
+impl State {
+    fn new(jinja: Arc<JinjaEnvironment>) -> Self {
+        Self {
+            render_queue: Vec::new(),
+            outputs: Vec::new(),
+            global_context: HashMap::new(),
+            script: PathBuf::new(),
+            reads: HashMap::new(),
+            requiring: Vec::new(),
+            hooks: Vec::new(),
+            extensions: HashSet::new(),
+            jinja,
+        }
+    }
+}
+
+/// Passes an output through every `on_output` hook matching its path relative to `dist`, in the order they were registered.
+pub fn run_hooks(hooks: &[Hook], name: &str, mut contents: Vec<u8>) -> eyre::Result<Vec<u8>> {
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    for hook in hooks
+        .iter()
+        .filter(|x| x.pattern.matches_with(name, options))
+    {
+        let result = hook
+            .callback
+            .call::<Value>((name, mlua::BString::from(contents.clone())))
+            .map_err(|err| {
+                eyre!(
+                    "on_output hook from {}: {}",
+                    hook.source.display_simple(),
+                    err
+                )
+            })?;
+
+        match result {
+            Value::String(x) => contents = x.as_bytes().to_vec(),
+            Value::Nil => {}
+            _ => {
+                return Err(eyre!(
+                    "on_output hook from {} must return a string or nil, got {}",
+                    hook.source.display_simple(),
+                    result.type_name()
+                ));
+            }
+        }
+    }
+
+    Ok(contents)
 }
 
 pub trait LuaFn {
@@ -80,19 +246,25 @@ impl<T: LuaFnReturn, E> LuaFnReturn for Result<T, E> {
     }
 }
 
-fn try_new_shebang() -> eyre::Result<Shebang> {
-    let lua = Lua::new();
+fn try_new_shebang(jinja: Arc<JinjaEnvironment>) -> eyre::Result<Shebang> {
+    let lua = sandbox::new_lua()?;
+
+    lua.set_app_data(State::new(jinja));
+
+    modules::install(&lua)?;
 
-    lua.set_app_data(State {
-        render_queue: Vec::new(),
-        global_context: HashMap::new(),
-    });
+    let strict = crate::args().strict();
 
     for fun in fns::all() {
         let name = fun.name();
 
         let cls = move |lua: &Lua, args| match fun.call(lua, args) {
             Ok(v) => Ok(v.into_lua(lua)?),
+            Err(err) if strict => Err(mlua::Error::RuntimeError(format!(
+                "function `{}` failed: {:#}",
+                fun.name(),
+                err
+            ))),
             Err(err) => {
                 error!(
                     "in {} (function `{}`) error: {:?}",
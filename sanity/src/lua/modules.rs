@@ -0,0 +1,242 @@
+use std::path::{Path, PathBuf};
+
+use mlua::{Lua, MultiValue, Table, Value};
+
+use crate::{
+    lua::{State, fns, sandbox},
+    paths::{self, PathExt as _},
+};
+
+/// Registry key of the original `require`.
+const FALLBACK: &str = "sanity.require";
+/// Registry key of the modules loaded so far, by canonical path.
+const MODULES: &str = "sanity.modules";
+
+/// Makes `require` look for modules next to the requiring script and in `www`, `_`-prefixed file names included.
+pub fn install(lua: &Lua) -> mlua::Result<()> {
+    let package: Table = lua.globals().get("package")?;
+    let loaders: Table = match package.get("loaders")? {
+        Value::Table(loaders) => loaders,
+        _ => package.get("searchers")?,
+    };
+
+    // Right after `package.preload`, ahead of the searchers going by the working directory.
+    // `require` below gets to these modules first; this only lists where it looked when nothing was found.
+    loaders.raw_insert(2, lua.create_function(search)?)?;
+
+    // `package.loaded` goes by name alone, which would hand every script whichever `helpers` was required first.
+    let fallback: mlua::Function = lua.globals().get("require")?;
+    lua.set_named_registry_value(FALLBACK, fallback)?;
+    lua.set_named_registry_value(MODULES, lua.create_table()?)?;
+    lua.globals()
+        .set("require", lua.create_function(require)?)?;
+    Ok(())
+}
+
+/// Loads a module found by `find` once per file, leaving everything else to the original `require`.
+fn require(lua: &Lua, name: String) -> mlua::Result<Value> {
+    let package: Table = lua.globals().get("package")?;
+    let preload: Table = package.get("preload")?;
+    let loaded: Table = package.get("loaded")?;
+
+    // Builtins such as `ffi` and preloaded modules come first, like they would otherwise.
+    let claimed = !preload.get::<Value>(name.as_str())?.is_nil()
+        || !loaded.get::<Value>(name.as_str())?.is_nil();
+    let found = match claimed {
+        true => None,
+        false => find(lua, &name)?.ok(),
+    };
+    let Some(path) = found else {
+        let fallback: mlua::Function = lua.named_registry_value(FALLBACK)?;
+        return fallback.call(name);
+    };
+
+    let modules: Table = lua.named_registry_value(MODULES)?;
+    let key = path.to_string_lossy();
+    if let Some(module) = modules.get::<Option<Value>>(&*key)? {
+        return Ok(module);
+    }
+
+    let cycle = lua
+        .app_data_ref::<State>()
+        .unwrap()
+        .requiring
+        .contains(&path);
+    if cycle {
+        return Err(mlua::Error::runtime(format!(
+            "loop while requiring `{}` from {}",
+            name,
+            path.display_simple()
+        )));
+    }
+
+    let result = loader(lua, path.clone())?.call::<MultiValue>(name)?;
+    let module = match result.into_iter().next() {
+        Some(Value::Nil) | None => Value::Boolean(true),
+        Some(module) => module,
+    };
+
+    modules.set(&*key, module.clone())?;
+    Ok(module)
+}
+
+fn search(lua: &Lua, name: String) -> mlua::Result<Value> {
+    match find(lua, &name)? {
+        Ok(path) => Ok(Value::Function(loader(lua, path)?)),
+        Err(tried) => Ok(Value::String(lua.create_string(tried)?)),
+    }
+}
+
+/// Finds a module next to the requiring script or module, then in `www`. Returns the places it looked otherwise.
+fn find(lua: &Lua, name: &str) -> mlua::Result<Result<PathBuf, String>> {
+    let current = {
+        let state = lua.app_data_ref::<State>().unwrap();
+        match state.requiring.last() {
+            Some(module) => module.parent().map(Path::to_path_buf),
+            None => state.script.parent().map(Path::to_path_buf),
+        }
+    };
+    let www = paths::www().map_err(mlua::Error::external)?;
+
+    let mut dirs: Vec<_> = current.into_iter().collect();
+    if !dirs.contains(&www) {
+        dirs.push(www.clone());
+    }
+
+    let mut tried = String::new();
+
+    for dir in &dirs {
+        for candidate in candidates(dir, name) {
+            if candidate.is_file() && sandbox::within(&www, &candidate) {
+                return Ok(Ok(candidate.canonicalize()?));
+            }
+            tried += &format!("\n\tno file '{}'", candidate.display_simple());
+        }
+    }
+
+    Ok(Err(tried))
+}
+
+/// `a.b` can be found as `a/b.lua`, `a/_b.lua`, or `a/b/init.lua`.
+fn candidates(dir: &Path, name: &str) -> Vec<PathBuf> {
+    let path = dir.join(name.replace('.', "/"));
+    let Some(stem) = path.file_name().map(|x| x.to_string_lossy().to_string()) else {
+        return Vec::new();
+    };
+
+    vec![
+        path.with_file_name(format!("{}.lua", stem)),
+        path.with_file_name(format!("_{}.lua", stem)),
+        path.join("init.lua"),
+    ]
+}
+
+fn loader(lua: &Lua, path: PathBuf) -> mlua::Result<mlua::Function> {
+    lua.create_function(move |lua, args: MultiValue| {
+        fns::track(lua, &path);
+
+        let source = std::fs::read(&path)?;
+        let chunk = lua
+            .load(source)
+            .set_name(format!("@{}", path.display_simple()))
+            .into_function()?;
+
+        // Modules required from this one are looked up next to it.
+        lua.app_data_mut::<State>()
+            .unwrap()
+            .requiring
+            .push(path.clone());
+        let result = chunk.call::<MultiValue>(args);
+        lua.app_data_mut::<State>().unwrap().requiring.pop();
+
+        result
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use super::*;
+    use crate::jinja2::JinjaEnvironment;
+
+    fn lua() -> Lua {
+        crate::test_config();
+        let lua = Lua::new();
+        lua.set_app_data(State::new(Arc::new(JinjaEnvironment::new())));
+        install(&lua).unwrap();
+        lua
+    }
+
+    fn require_from(lua: &Lua, script: &Path, name: &str) -> String {
+        lua.app_data_mut::<State>().unwrap().script = script.to_path_buf();
+        let chunk = format!("return require({:?}).name", name);
+        lua.load(chunk).eval().unwrap()
+    }
+
+    #[test]
+    fn same_names_in_different_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        for dir in ["a", "b"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            let module = format!("loads = (loads or 0) + 1; return {{ name = {:?} }}", dir);
+            fs::write(root.join(dir).join("_helpers.lua"), module).unwrap();
+        }
+
+        let lua = lua();
+        let a = root.join("a/x.lua");
+        let b = root.join("b/y.lua");
+        assert_eq!(require_from(&lua, &a, "helpers"), "a");
+        assert_eq!(require_from(&lua, &b, "helpers"), "b");
+        assert_eq!(require_from(&lua, &a, "helpers"), "a");
+        assert_eq!(lua.globals().get::<i64>("loads").unwrap(), 2);
+    }
+
+    #[test]
+    fn modules_look_next_to_themselves() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        fs::create_dir_all(root.join("lib/util")).unwrap();
+        fs::write(root.join("_helpers.lua"), "return { name = 'top' }").unwrap();
+        fs::write(
+            root.join("lib/util/_helpers.lua"),
+            "return { name = 'util' }",
+        )
+        .unwrap();
+        fs::write(
+            root.join("lib/util/init.lua"),
+            "return { name = require('helpers').name }",
+        )
+        .unwrap();
+
+        let lua = lua();
+        let script = root.join("index.lua");
+        assert_eq!(require_from(&lua, &script, "lib.util"), "util");
+        assert_eq!(require_from(&lua, &script, "helpers"), "top");
+    }
+
+    #[test]
+    fn builtins_and_loops() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        fs::write(root.join("_loop.lua"), "return require('loop')").unwrap();
+
+        let lua = lua();
+        lua.app_data_mut::<State>().unwrap().script = root.join("x.lua");
+        let string: bool = lua
+            .load("return require('string') == string")
+            .eval()
+            .unwrap();
+        assert!(string);
+
+        let err = lua.load("require('loop')").exec().unwrap_err().to_string();
+        assert!(err.contains("loop while requiring"), "{}", err);
+        let err = lua
+            .load("require('missing')")
+            .exec()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("_missing.lua"), "{}", err);
+    }
+}
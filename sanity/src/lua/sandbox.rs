@@ -0,0 +1,238 @@
+use std::path::{Component, Path, PathBuf};
+
+use color_eyre::eyre::{self, eyre};
+use mlua::{Lua, LuaOptions, StdLib, Table, Value};
+
+use crate::paths::PathExt as _;
+
+/// Everything the sandbox takes away from scripts, by the name it can be given back with.
+pub const CAPABILITIES: &[&str] = &[
+    "io",
+    "os.execute",
+    "os.exit",
+    "os.getenv",
+    "os.remove",
+    "os.rename",
+    "os.tmpname",
+    "loadfile",
+    "dofile",
+    "load",
+    "ffi",
+    "debug",
+];
+
+/// Whether scripts run sandboxed, either because `sanity.toml` or the command line says so.
+pub fn enabled() -> bool {
+    crate::args().sandbox || crate::config().lua.sandbox
+}
+
+/// Whether scripts get a capability. `ffi` and `debug` have to be asked for even outside the sandbox.
+fn allowed(capability: &str) -> bool {
+    // Capabilities come from the project itself, so `--sandbox` doesn't trust them.
+    if crate::args().sandbox {
+        return false;
+    }
+
+    let config = &crate::config().lua;
+    let asked = config.allow.iter().any(|x| x == capability);
+    asked || !(config.sandbox || matches!(capability, "ffi" | "debug"))
+}
+
+/// Rejects capability names that don't exist, so that typos don't go unnoticed.
+pub fn validate(allow: &[String]) -> eyre::Result<()> {
+    match allow.iter().find(|x| !CAPABILITIES.contains(&x.as_str())) {
+        Some(unknown) => Err(eyre!(
+            "unknown Lua capability `{}`; expected one of: {}",
+            unknown,
+            CAPABILITIES.join(", ")
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Creates a Lua state with the standard libraries the project is allowed to use.
+pub fn new_lua() -> eyre::Result<Lua> {
+    let mut libs = StdLib::ALL_SAFE;
+    if allowed("ffi") {
+        libs |= StdLib::FFI;
+    }
+    if allowed("debug") {
+        libs |= StdLib::DEBUG;
+    }
+
+    let lua = if libs == StdLib::ALL_SAFE {
+        Lua::new()
+    } else {
+        // SAFETY: the project asked for these libraries in its config.
+        unsafe { Lua::unsafe_new_with(libs, LuaOptions::default()) }
+    };
+
+    if enabled() {
+        restrict(&lua)?;
+    }
+
+    Ok(lua)
+}
+
+fn restrict(lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    let os: Table = globals.get("os")?;
+    let package: Table = globals.get("package")?;
+    let loaded: Table = package.get("loaded")?;
+
+    for capability in CAPABILITIES.iter().filter(|x| !allowed(x)) {
+        match *capability {
+            "io" => {
+                globals.set("io", Value::Nil)?;
+                loaded.set("io", Value::Nil)?;
+            }
+            "load" => {
+                // Hand-crafted bytecode can break out of LuaJIT, so only ever load source code.
+                lua.load(
+                    r#"
+                    local load = load
+                    _G.load = function(chunk, name) return load(chunk, name, "t") end
+                    _G.loadstring = function(chunk, name) return load(chunk, name, "t") end
+                    "#,
+                )
+                .exec()?;
+            }
+            "ffi" | "debug" => {} // not loaded in the first place
+            name => match name.strip_prefix("os.") {
+                Some(name) => os.set(name, Value::Nil)?,
+                None => globals.set(name, Value::Nil)?,
+            },
+        }
+    }
+
+    // Modules only ever come from `www`.
+    package.set("path", "")?;
+    package.set("cpath", "")?;
+    package.set("loadlib", Value::Nil)?;
+
+    Ok(())
+}
+
+/// Resolves a path relative to `base`, refusing to leave it while sandboxed.
+pub fn confine(base: &Path, relative: &str) -> eyre::Result<PathBuf> {
+    match enabled() {
+        true => contain(base, relative),
+        false => Ok(base.join(relative)),
+    }
+}
+
+fn contain(base: &Path, relative: &str) -> eyre::Result<PathBuf> {
+    let path = base.join(relative);
+    let outside = || {
+        eyre!(
+            "sandbox: `{}` is outside of {}",
+            relative,
+            base.display_simple()
+        )
+    };
+
+    let mut depth = 0usize;
+    for comp in Path::new(relative).components() {
+        match comp {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => depth = depth.checked_sub(1).ok_or_else(outside)?,
+            Component::RootDir | Component::Prefix(_) => return Err(outside()),
+        }
+    }
+
+    // Symlinks could still lead elsewhere.
+    if path.exists() && !inside(base, &path) {
+        return Err(outside());
+    }
+
+    Ok(path)
+}
+
+/// Whether an existing file really is inside `base`, symlinks resolved. Always true outside the sandbox.
+pub fn within(base: &Path, path: &Path) -> bool {
+    !enabled() || inside(base, path)
+}
+
+fn inside(base: &Path, path: &Path) -> bool {
+    let base = base.canonicalize().unwrap_or_else(|_| base.to_path_buf());
+    path.canonicalize().is_ok_and(|x| x.starts_with(base))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn contain_relative_paths() {
+        let base = Path::new("/site/www");
+        assert_eq!(contain(base, "a/b.txt").unwrap(), base.join("a/b.txt"));
+        assert_eq!(contain(base, "./a").unwrap(), base.join("./a"));
+        assert!(contain(base, "a/../b").is_ok());
+        assert!(contain(base, "").is_ok());
+    }
+
+    #[test]
+    fn contain_refuses_escapes() {
+        let base = Path::new("/site/www");
+        assert!(contain(base, "..").is_err());
+        assert!(contain(base, "../dist/x").is_err());
+        assert!(contain(base, "a/../../x").is_err());
+        assert!(contain(base, "a/./../b/../../x").is_err());
+        assert!(contain(base, "/etc/passwd").is_err());
+    }
+
+    #[test]
+    fn contain_refuses_prefixes() {
+        let base = Path::new("/site/www");
+        // Only a prefix on Windows; elsewhere it's an odd but harmless file name.
+        for relative in ["C:/x", "C:x", r"C:\x", r"\\server\share\x"] {
+            let result = contain(base, relative);
+            assert!(
+                result.is_err() || result.unwrap().starts_with(base),
+                "{}",
+                relative
+            );
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn contain_refuses_windows_prefixes() {
+        let base = Path::new(r"C:\site\www");
+        assert!(contain(base, r"C:\x").is_err());
+        assert!(contain(base, r"D:x").is_err());
+        assert!(contain(base, r"\\server\share\x").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_cannot_escape() {
+        let root = tempfile::tempdir().unwrap();
+        let base = root.path().join("www");
+        let outside = root.path().join("secret");
+        fs::create_dir_all(base.join("dir")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("key"), "x").unwrap();
+        fs::write(base.join("dir/file"), "x").unwrap();
+        std::os::unix::fs::symlink(&outside, base.join("link")).unwrap();
+        std::os::unix::fs::symlink(base.join("dir"), base.join("alias")).unwrap();
+
+        assert!(contain(&base, "link/key").is_err());
+        assert!(contain(&base, "link").is_err());
+        assert!(!inside(&base, &base.join("link/key")));
+
+        assert!(contain(&base, "alias/file").is_ok());
+        assert!(inside(&base, &base.join("alias/file")));
+        assert!(inside(&base, &base.join("dir/file")));
+    }
+
+    #[test]
+    fn missing_files_are_not_inside() {
+        let root = tempfile::tempdir().unwrap();
+        assert!(!inside(root.path(), &root.path().join("missing")));
+        assert!(contain(root.path(), "missing/file").is_ok());
+    }
+}
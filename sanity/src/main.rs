@@ -2,22 +2,21 @@
 extern crate log;
 
 use std::{
-    collections::HashSet,
     convert::Infallible,
     net::SocketAddr,
-    path::Path,
+    path::{Path, PathBuf},
     process::ExitCode,
-    sync::{OnceLock, mpsc},
+    sync::OnceLock,
     time::Duration,
 };
 
 use clap::{Parser, Subcommand};
 use color_eyre::eyre::{self, eyre};
-use http_body_util::Full;
+use http_body_util::{Either, Full, channel::Channel};
 use hyper::{
     Request, Response,
     body::{Bytes, Incoming},
-    header::CONTENT_TYPE,
+    header::{CACHE_CONTROL, CONTENT_TYPE},
     server::conn::http1,
     service::service_fn,
 };
@@ -27,19 +26,34 @@ use notify_debouncer_full::{
     DebouncedEvent, new_debouncer,
     notify::{EventKind, RecursiveMode},
 };
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, sync::mpsc};
 
 use crate::{fs::PathExt, paths::PathExt as _};
 
+mod assets;
 mod build;
+mod config;
+mod data;
+mod deps;
+mod diagnostic;
+mod feed;
+mod filters;
+mod frontmatter;
 mod fs;
 mod jinja2;
 mod lua;
+mod manifest;
+mod markdown;
 mod minify;
 mod paths;
+mod reload;
+mod sitemap;
+mod xml;
 
 const DEBOUNCE_TIMEOUT: Duration = Duration::from_millis(1000);
 
+type Body = Either<Full<Bytes>, Channel<Bytes>>;
+
 /// The only sane static site generator in existence.
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
@@ -61,6 +75,30 @@ pub struct Args {
     /// Output build times in milliseconds. Useful for profiling.
     #[arg(short, long)]
     profile_build_times: bool,
+    /// Use a different input directory than the one set in `sanity.toml` (`www` by default).
+    #[arg(long, value_name = "DIR")]
+    www: Option<PathBuf>,
+    /// Use a different output directory than the one set in `sanity.toml` (`dist` by default).
+    #[arg(long, value_name = "DIR")]
+    dist: Option<PathBuf>,
+    /// Override the site's base URL set in `sanity.toml`.
+    #[arg(long, value_name = "URL")]
+    base_url: Option<String>,
+    /// Disable minification regardless of what `sanity.toml` says.
+    #[arg(long)]
+    no_minify: bool,
+    /// Keep outputs of the previous build which the current one didn't produce, e.g. of deleted or renamed files.
+    #[arg(long)]
+    no_prune: bool,
+    /// Fail the build when a Lua builtin such as `json` or `read` errors out. The default for `build`.
+    #[arg(long, conflicts_with = "lenient")]
+    strict: bool,
+    /// Run Lua scripts sandboxed with no extra capabilities, regardless of what `sanity.toml` says. Meant for building untrusted changes.
+    #[arg(long)]
+    sandbox: bool,
+    /// Log errors from Lua builtins and hand `nil` to the script instead. The default for `watch` and `server`.
+    #[arg(long)]
+    lenient: bool,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -71,11 +109,11 @@ pub enum Commands {
     Clean,
     /// Run a filesystem watcher which rebuilds the project on start and on changes inside `www`.
     Watch,
-    /// Run an HTTP dev-server with a filesystem watcher on http://localhost:8000 (or a different port, if `--port` is specified).
+    /// Run an HTTP dev-server with a filesystem watcher on http://localhost:8000 (or a different port, if `--port` or `sanity.toml` specifies one).
     Server {
         /// Set the listening port for the dev-server.
-        #[arg(short, long, default_value_t = 8000)]
-        port: u16,
+        #[arg(short, long)]
+        port: Option<u16>,
     },
     /// Write Lua function definitions to disk.
     ///
@@ -106,6 +144,14 @@ async fn realmain() -> eyre::Result<ExitCode> {
 
     ARGS.set(Args::parse()).unwrap();
 
+    match config::Config::load(args()) {
+        Ok(config) => CONFIG.set(config).unwrap(),
+        Err(err) => {
+            error!("Failed to load the project configuration: {}", err);
+            return Ok(ExitCode::FAILURE);
+        }
+    }
+
     match args().command() {
         Commands::Build => {
             if build::run().await.is_err() {
@@ -123,7 +169,7 @@ async fn realmain() -> eyre::Result<ExitCode> {
         }
         Commands::Server { port } => {
             let watch = tokio::spawn(watch());
-            run_server(port).await?;
+            run_server(port.unwrap_or(config().server.port)).await?;
             watch.await??;
         }
     };
@@ -156,20 +202,56 @@ async fn run_server(port: u16) -> eyre::Result<()> {
     }
 }
 
-async fn http_service(
-    req: Request<Incoming>,
-) -> core::result::Result<Response<Full<Bytes>>, Infallible> {
+async fn http_service(req: Request<Incoming>) -> core::result::Result<Response<Body>, Infallible> {
+    // This one's long-lived, so it mustn't hold the build lock.
+    if req.uri().path() == reload::EVENTS_PATH {
+        return Ok(reload_events());
+    }
+
     let _lock = build::lock().await;
     let query = req.uri().path()[1..].to_string();
 
+    // Pages show what broke the build until it's fixed; the reload script brings them back afterwards.
+    if let Some(failure) = build::failure()
+        && matches!(Path::new(&query).extension_str(), None | Some("html"))
+    {
+        return Ok(error_page(&query, &failure));
+    }
+
     let err = match _http_service(req) {
-        Ok(ok) => return Ok(ok),
+        Ok(ok) => return Ok(ok.map(Either::Left)),
         Err(err) => err,
     };
 
     error!("{:?} -> {:?}", query, err);
-    let fuckyou = format!(include_str!("error.html"), query, err);
-    Ok(Response::new(Full::new(Bytes::from(fuckyou))))
+    let report = format!("<p><code>{}</code></p>", xml::escape(&err.to_string()));
+    Ok(error_page(&query, &report))
+}
+
+fn error_page(query: &str, report: &str) -> Response<Body> {
+    let fuckyou = format!(include_str!("error.html"), xml::escape(query), report);
+    let fuckyou = reload::inject(fuckyou.into_bytes());
+    Response::new(Either::Left(Full::new(Bytes::from(fuckyou))))
+}
+
+fn reload_events() -> Response<Body> {
+    let (mut tx, body) = Channel::new(1);
+    let mut changes = reload::subscribe();
+
+    tokio::spawn(async move {
+        while let Ok(change) = changes.recv().await {
+            let event = Bytes::from(reload::event(change));
+            if tx.send_data(event).await.is_err() {
+                break; // the browser went away
+            }
+        }
+    });
+
+    let mut res = Response::new(Either::Right(body));
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, "text/event-stream".parse().unwrap());
+    headers.insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+    res
 }
 
 fn _http_service(req: Request<Incoming>) -> eyre::Result<Response<Full<Bytes>>> {
@@ -194,7 +276,11 @@ fn _http_service(req: Request<Incoming>) -> eyre::Result<Response<Full<Bytes>>>
         }
     }
 
-    let data = std::fs::read(out_path.clone())?;
+    let mut data = std::fs::read(out_path.clone())?;
+    if let Some("html") = out_path.extension_str() {
+        data = reload::inject(data);
+    }
+
     let mut res = Response::new(Full::new(Bytes::from(data)));
 
     if let Some(x) = match out_path.extension_str() {
@@ -212,30 +298,33 @@ fn _http_service(req: Request<Incoming>) -> eyre::Result<Response<Full<Bytes>>>
 }
 
 async fn process_events(events: Vec<DebouncedEvent>) -> eyre::Result<()> {
-    let mut targets = HashSet::new();
+    let mut redo = false;
+    // Fingerprinted stylesheets move to a new URL whenever they change, which only the page knows about.
+    let mut change = match config().fingerprint {
+        true => reload::Change::Page,
+        false => reload::Change::Css,
+    };
 
     for event in events {
-        if !matches!(event.kind, EventKind::Remove(_) | EventKind::Modify(_)) {
+        if !matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Remove(_) | EventKind::Modify(_)
+        ) {
             continue;
         }
 
         for path in &event.paths {
-            let path = path.strip_prefix(paths::www()?)?;
-            let path = paths::dist()?.join(path);
-            if !path.is_dir() {
-                targets.insert(path);
+            if !matches!(path.extension_str(), Some("scss" | "css")) {
+                change = reload::Change::Page;
             }
-        }
-    }
-
-    let redo = !targets.is_empty();
 
-    for path in targets {
-        let _ = std::fs::remove_file(path);
+            // Outputs of removed files get pruned by the build itself.
+            redo = true;
+        }
     }
 
-    if redo {
-        let _ = build::run().await;
+    if redo && build::run().await.is_ok() {
+        reload::notify(change);
     }
 
     Ok(())
@@ -244,13 +333,16 @@ async fn process_events(events: Vec<DebouncedEvent>) -> eyre::Result<()> {
 async fn watch() -> eyre::Result<()> {
     let _ = build::run().await;
 
-    let (tx, rx) = mpsc::channel();
-    let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, None, tx)?;
+    // A blocking receiver would hog a runtime worker along with any tasks it wakes up.
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut debouncer = new_debouncer(DEBOUNCE_TIMEOUT, None, move |result| {
+        let _ = tx.send(result);
+    })?;
 
     debouncer.watch(&paths::www()?, RecursiveMode::Recursive)?;
     info!("Watching {}", paths::www()?.display_simple());
 
-    for result in rx {
+    while let Some(result) = rx.recv().await {
         match result {
             Ok(events) => {
                 if let Err(error) = process_events(events).await {
@@ -269,11 +361,26 @@ async fn watch() -> eyre::Result<()> {
 }
 
 static ARGS: OnceLock<Args> = OnceLock::new();
+static CONFIG: OnceLock<config::Config> = OnceLock::new();
 
 pub fn args() -> &'static Args {
     ARGS.get().unwrap()
 }
 
+pub fn config() -> &'static config::Config {
+    CONFIG.get().unwrap()
+}
+
+/// Gives unit tests the default arguments and configuration, with `base_url` set to `https://example.org`.
+#[cfg(test)]
+pub fn test_config() {
+    let _ = ARGS.set(Args::parse_from(["sanity"]));
+    let _ = CONFIG.set(config::Config {
+        base_url: Some("https://example.org".to_string()),
+        ..Default::default()
+    });
+}
+
 impl Args {
     pub fn command(&self) -> Commands {
         self.command.clone().unwrap_or(Commands::Build)
@@ -282,4 +389,9 @@ impl Args {
     pub fn prod(&self) -> bool {
         self.force_prod || matches!(self.command(), Commands::Build)
     }
+
+    /// Whether errors in Lua builtins are raised as Lua errors rather than turned into `nil`.
+    pub fn strict(&self) -> bool {
+        self.strict || (!self.lenient && matches!(self.command(), Commands::Build))
+    }
 }
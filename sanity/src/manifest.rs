@@ -0,0 +1,193 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use color_eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::paths::{self, PathExt as _};
+
+/// Name of the build manifest inside `dist`.
+pub const FILENAME: &str = ".sanity-manifest.json";
+
+/// Every file produced by a build, relative to `dist`.
+#[derive(Serialize, Deserialize, Default)]
+struct Manifest {
+    files: BTreeSet<String>,
+}
+
+/// Deletes the files produced by the previous build but not by this one (unless told not to prune), then records this build's outputs.
+pub fn update(outputs: &HashSet<PathBuf>, prune: bool) -> eyre::Result<()> {
+    let dist = paths::dist()?;
+    let path = dist.join(FILENAME);
+
+    let mut current = Manifest {
+        files: outputs.iter().filter_map(|x| relative(&dist, x)).collect(),
+    };
+
+    if let Some(previous) = read(&path) {
+        let stale: Vec<_> = previous.files.difference(&current.files).cloned().collect();
+
+        for entry in stale {
+            if !prune {
+                // Remember it so a later build can prune it after all.
+                current.files.insert(entry);
+            } else if let Some(stale) = resolve(&dist, &entry) {
+                remove(&dist, &stale);
+            }
+        }
+    }
+
+    fs::write(path, serde_json::to_string_pretty(&current)?)?;
+    Ok(())
+}
+
+fn read(path: &Path) -> Option<Manifest> {
+    let data = fs::read(path).ok()?;
+
+    match serde_json::from_slice(&data) {
+        Ok(manifest) => Some(manifest),
+        Err(err) => {
+            warn!("Ignoring a broken {}: {}", FILENAME, err);
+            None
+        }
+    }
+}
+
+/// Deletes a stale output along with any directories it leaves empty.
+fn remove(dist: &Path, path: &Path) {
+    if fs::remove_file(path).is_err() {
+        return;
+    }
+    info!("Pruned {}", path.display_simple());
+
+    for dir in path.ancestors().skip(1) {
+        if dir == dist || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+}
+
+fn relative(dist: &Path, path: &Path) -> Option<String> {
+    let mut parts = Vec::new();
+
+    for comp in path.strip_prefix(dist).ok()?.components() {
+        match comp {
+            Component::Normal(x) => parts.push(x.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+
+    Some(parts.join("/"))
+}
+
+/// Turns a manifest entry back into a path, refusing anything that would escape `dist`.
+fn resolve(dist: &Path, entry: &str) -> Option<PathBuf> {
+    let mut path = dist.to_path_buf();
+
+    for part in entry.split('/') {
+        match Path::new(part).components().next() {
+            Some(Component::Normal(_)) => path.push(part),
+            _ => return None,
+        }
+    }
+
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_names() {
+        let dist = Path::new("/site/dist");
+        assert_eq!(relative(dist, &dist.join("a/b.html")).unwrap(), "a/b.html");
+        assert_eq!(relative(dist, &dist.join("./a")).unwrap(), "a");
+        assert!(relative(dist, &dist.join("../www/x")).is_none());
+        assert!(relative(dist, Path::new("/site/www/x")).is_none());
+    }
+
+    #[test]
+    fn resolve_entries() {
+        let dist = Path::new("/site/dist");
+        assert_eq!(
+            resolve(dist, "a/b.html").unwrap(),
+            dist.join("a").join("b.html")
+        );
+        assert_eq!(
+            resolve(dist, "index.html").unwrap(),
+            dist.join("index.html")
+        );
+    }
+
+    #[test]
+    fn resolve_refuses_escapes() {
+        let dist = Path::new("/site/dist");
+        for entry in [
+            "..",
+            "../www/x",
+            "a/../../x",
+            "/etc/passwd",
+            "",
+            "a//b",
+            "./a",
+            "a/.",
+        ] {
+            assert!(resolve(dist, entry).is_none(), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn resolve_refuses_prefixes() {
+        let dist = Path::new("/site/dist");
+        // Only a prefix on Windows; elsewhere it's an odd but harmless file name.
+        for entry in ["C:/x", "C:x", r"C:\x", r"\\server\share\x"] {
+            let path = resolve(dist, entry);
+            assert!(path.is_none_or(|x| x.starts_with(dist)), "{}", entry);
+        }
+    }
+
+    #[test]
+    fn remove_prunes_empty_dirs() {
+        let root = tempfile::tempdir().unwrap();
+        let dist = root.path().join("dist");
+        fs::create_dir_all(dist.join("a/b/c")).unwrap();
+        fs::write(dist.join("a/b/c/page.html"), "x").unwrap();
+        fs::write(dist.join("a/keep.txt"), "x").unwrap();
+
+        remove(&dist, &dist.join("a/b/c/page.html"));
+        assert!(!dist.join("a/b").exists());
+        assert!(dist.join("a/keep.txt").exists());
+
+        remove(&dist, &dist.join("a/keep.txt"));
+        assert!(!dist.join("a").exists());
+        assert!(dist.exists());
+        assert!(root.path().exists());
+    }
+
+    #[test]
+    fn remove_leaves_missing_files_alone() {
+        let root = tempfile::tempdir().unwrap();
+        let dist = root.path().join("dist");
+        fs::create_dir_all(dist.join("empty")).unwrap();
+
+        remove(&dist, &dist.join("empty/missing.html"));
+        assert!(dist.join("empty").exists());
+    }
+
+    #[test]
+    fn remove_stops_at_dist() {
+        let root = tempfile::tempdir().unwrap();
+        let dist = root.path().join("nested/dist");
+        fs::create_dir_all(&dist).unwrap();
+        fs::write(dist.join("page.html"), "x").unwrap();
+
+        remove(&dist, &dist.join("page.html"));
+        assert!(dist.exists());
+        assert!(root.path().join("nested").exists());
+    }
+}
@@ -0,0 +1,39 @@
+use pulldown_cmark::{Options, Parser, html};
+
+/// A Markdown document split into its layout directive and body.
+pub struct Document<'a> {
+    pub layout: Option<&'a str>,
+    pub body: &'a str,
+}
+
+/// Splits off the optional `<!-- layout: _name.html -->` directive on the first line.
+pub fn parse(source: &str) -> Document<'_> {
+    let (first, rest) = source.split_once('\n').unwrap_or((source, ""));
+
+    let layout = first
+        .trim()
+        .strip_prefix("<!--")
+        .and_then(|x| x.strip_suffix("-->"))
+        .and_then(|x| x.trim().strip_prefix("layout:"))
+        .map(str::trim)
+        .filter(|x| !x.is_empty());
+
+    match layout {
+        Some(_) => Document { layout, body: rest },
+        None => Document {
+            layout: None,
+            body: source,
+        },
+    }
+}
+
+pub fn render(source: &str) -> String {
+    let options = Options::ENABLE_TABLES
+        | Options::ENABLE_FOOTNOTES
+        | Options::ENABLE_STRIKETHROUGH
+        | Options::ENABLE_TASKLISTS;
+
+    let mut out = String::with_capacity(source.len() * 3 / 2);
+    html::push_html(&mut out, Parser::new_ext(source, options));
+    out
+}
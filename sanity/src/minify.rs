@@ -18,11 +18,12 @@ pub fn write(target: &Path, file_type: Type, data: impl Into<Vec<u8>>) -> eyre::
     let orig_data = data.into();
     let data = orig_data.clone();
     let prod = crate::args().prod();
+    let switches = &crate::config().minify;
 
     let minified = match file_type {
-        Type::Html if prod => html(data),
-        Type::Js if prod => js(data),
-        _ => Ok(data.into()),
+        Type::Html if prod && switches.html => html(data),
+        Type::Js if prod && switches.js => js(data),
+        _ => Ok(data),
     };
 
     match minified {
@@ -1,4 +1,10 @@
-use std::{fmt::Display, path::Path};
+use std::{
+    ffi::OsStr,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use crate::fs::PathExt as _;
 
 type SeriousPath = color_eyre::eyre::Result<std::path::PathBuf>;
 
@@ -7,11 +13,47 @@ pub fn root() -> SeriousPath {
 }
 
 pub fn www() -> SeriousPath {
-    Ok(root()?.join("www"))
+    Ok(root()?.join(&crate::config().input))
 }
 
 pub fn dist() -> SeriousPath {
-    Ok(root()?.join("dist"))
+    Ok(root()?.join(&crate::config().output))
+}
+
+/// Directory of the data files exposed as `data`.
+pub fn data() -> SeriousPath {
+    Ok(www()?.join(&crate::config().data))
+}
+
+/// Site-relative URL of an output file, e.g. `/blog/` for `dist/blog/index.html`.
+pub fn url(target: &Path) -> color_eyre::eyre::Result<String> {
+    Ok(link(&target.dist_name()?))
+}
+
+/// Site-relative URL of a path relative to `dist`, with `index.html` left off.
+pub fn link(name: &str) -> String {
+    let name = name.trim_start_matches('/');
+
+    let name = match name.strip_suffix("index.html") {
+        Some(dir) if dir.is_empty() || dir.ends_with('/') => dir,
+        _ => name,
+    };
+
+    format!("/{}", name)
+}
+
+/// Where an HTML page is written: `about.html` becomes `about/index.html` with `pretty_urls` on.
+///
+/// Index pages and `404.html` stay where they are.
+pub fn pretty(target: PathBuf) -> PathBuf {
+    if !crate::config().pretty_urls || target.extension_str() != Some("html") {
+        return target;
+    }
+
+    match target.file_stem().and_then(OsStr::to_str) {
+        Some("index" | "404") | None => target,
+        Some(_) => target.with_extension("").join("index.html"),
+    }
 }
 
 pub trait PathExt {
@@ -0,0 +1,20 @@
+(() => {
+    const events = new EventSource("/__sanity/events");
+
+    events.onmessage = (event) => {
+        if (event.data !== "css") {
+            location.reload();
+            return;
+        }
+
+        for (const link of document.querySelectorAll('link[rel="stylesheet"]')) {
+            const url = new URL(link.href);
+            if (url.origin !== location.origin) {
+                continue;
+            }
+
+            url.searchParams.set("__sanity", Date.now());
+            link.href = url.href;
+        }
+    };
+})();
@@ -0,0 +1,54 @@
+use std::sync::LazyLock;
+
+use tokio::sync::broadcast;
+
+/// Path of the server-sent events endpoint the injected script listens to.
+pub const EVENTS_PATH: &str = "/__sanity/events";
+
+const SCRIPT: &str = include_str!("reload.js");
+
+#[derive(Clone, Copy, Debug)]
+pub enum Change {
+    /// Anything that requires a full page reload.
+    Page,
+    /// Only stylesheets changed; these can be swapped in place.
+    Css,
+}
+
+static CHANNEL: LazyLock<broadcast::Sender<Change>> = LazyLock::new(|| broadcast::channel(16).0);
+
+/// Tells every connected browser to reload.
+pub fn notify(change: Change) {
+    // Fails when nobody is listening, which is fine.
+    let _ = CHANNEL.send(change);
+}
+
+pub fn subscribe() -> broadcast::Receiver<Change> {
+    CHANNEL.subscribe()
+}
+
+/// Formats a change as a server-sent event.
+pub fn event(change: Change) -> String {
+    let data = match change {
+        Change::Page => "reload",
+        Change::Css => "css",
+    };
+    format!("data: {}\n\n", data)
+}
+
+/// Injects the live-reload client script into an HTML document.
+pub fn inject(html: Vec<u8>) -> Vec<u8> {
+    let script = format!("<script>{}</script>", SCRIPT);
+    let needle = b"</body>";
+
+    let at = html
+        .windows(needle.len())
+        .rposition(|x| x.eq_ignore_ascii_case(needle))
+        .unwrap_or(html.len());
+
+    let mut out = Vec::with_capacity(html.len() + script.len());
+    out.extend_from_slice(&html[..at]);
+    out.extend_from_slice(script.as_bytes());
+    out.extend_from_slice(&html[at..]);
+    out
+}
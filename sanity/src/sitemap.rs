@@ -0,0 +1,70 @@
+use std::{collections::HashMap, fmt::Write as _, fs, path::PathBuf, time::SystemTime};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use color_eyre::eyre::{self, eyre};
+
+use crate::{paths, xml};
+
+pub struct Entry {
+    pub target: PathBuf,
+    pub lastmod: Option<SystemTime>,
+}
+
+/// Writes `sitemap.xml` and, if enabled, `robots.txt`, unless the site already provides its own.
+///
+/// Returns the paths of the files written.
+pub fn write(
+    mut entries: Vec<Entry>,
+    outputs: &HashMap<PathBuf, PathBuf>,
+) -> eyre::Result<Vec<PathBuf>> {
+    let config = crate::config();
+    let base = config
+        .base_url
+        .as_ref()
+        .ok_or_else(|| eyre!("Generating a sitemap requires `base_url` to be set"))?;
+
+    let mut written = Vec::new();
+
+    let sitemap = paths::dist()?.join("sitemap.xml");
+    if outputs.contains_key(&sitemap) {
+        warn!("Not overwriting the sitemap.xml provided by the site");
+    } else {
+        entries.sort_by(|a, b| a.target.cmp(&b.target));
+
+        let mut xml = String::new();
+        writeln!(xml, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(
+            xml,
+            r#"<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">"#
+        )?;
+
+        for entry in entries {
+            let url = format!("{}{}", base, paths::url(&entry.target)?);
+            writeln!(xml, "  <url>")?;
+            writeln!(xml, "    <loc>{}</loc>", xml::escape(&url))?;
+            if let Some(lastmod) = entry.lastmod {
+                let lastmod: DateTime<Utc> = lastmod.into();
+                let lastmod = lastmod.to_rfc3339_opts(SecondsFormat::Secs, true);
+                writeln!(xml, "    <lastmod>{}</lastmod>", lastmod)?;
+            }
+            writeln!(xml, "  </url>")?;
+        }
+
+        writeln!(xml, "</urlset>")?;
+        fs::write(&sitemap, xml)?;
+        written.push(sitemap);
+    }
+
+    let robots = paths::dist()?.join("robots.txt");
+    if !config.sitemap.robots {
+        // the user doesn't want one
+    } else if outputs.contains_key(&robots) {
+        warn!("Not overwriting the robots.txt provided by the site");
+    } else {
+        let txt = format!("User-agent: *\nAllow: /\n\nSitemap: {}/sitemap.xml\n", base);
+        fs::write(&robots, txt)?;
+        written.push(robots);
+    }
+
+    Ok(written)
+}